@@ -0,0 +1,98 @@
+use bevy_renet::netcode::{ConnectToken, generate_random_bytes};
+use protocol::PROTOCOL_ID;
+use protocol::auth::{PRIVATE_KEY_ENV, PrivateKey, read_token_request};
+use std::env;
+use std::error::Error;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug)]
+struct AuthSettings {
+    port: u16,
+    server_addresses: Vec<SocketAddr>,
+    private_key: PrivateKey,
+    token_expire_seconds: u64,
+    client_timeout_seconds: i32,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            port: env::var("AUTH_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(5001),
+            server_addresses: env::var("SERVER_ADDRESSES")
+                .unwrap_or_else(|_| "127.0.0.1:5000".to_string())
+                .split(',')
+                .map(|addr| {
+                    addr.trim()
+                        .parse()
+                        .expect("SERVER_ADDRESSES must be a comma separated list of ip:port")
+                })
+                .collect(),
+            private_key: PrivateKey::from_env().unwrap_or_else(|| panic!("{} must be set", PRIVATE_KEY_ENV)),
+            token_expire_seconds: env::var("TOKEN_EXPIRE_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(300),
+            client_timeout_seconds: env::var("CLIENT_TIMEOUT_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(15),
+        }
+    }
+}
+
+/// Run the connect token service, or print a fresh private key with `--generate-key`
+fn main() {
+    if env::args().any(|arg| arg == "--generate-key") {
+        println!("{}", PrivateKey(generate_random_bytes()).to_hex());
+        return;
+    }
+
+    let settings = Arc::new(AuthSettings::default());
+    println!("{:?}", settings);
+    let listener = TcpListener::bind(("0.0.0.0", settings.port)).unwrap();
+    println!("Token service listening on port: {}", settings.port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let settings = settings.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            match issue_token(stream, &settings) {
+                Ok(client_id) => println!("Issued token for client {} to {:?}", client_id, peer),
+                Err(e) => eprintln!("Failed to issue token to {:?}: {}", peer, e),
+            }
+        });
+    }
+}
+
+/// Read a token request and answer with a signed connect token for a freshly assigned client id
+fn issue_token(mut stream: TcpStream, settings: &AuthSettings) -> Result<u64, Box<dyn Error>> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = read_token_request(&mut stream)?;
+    // The id is chosen here rather than by the client, so nobody can connect as another player
+    let client_id = u64::from_le_bytes(generate_random_bytes());
+    // Netcode tries the addresses in order, so a client following a redirect lands on the server it was sent to
+    let mut server_addresses = settings.server_addresses.clone();
+    if let Some(index) = request
+        .preferred_server
+        .and_then(|preferred| server_addresses.iter().position(|&addr| addr == preferred))
+    {
+        server_addresses[..=index].rotate_right(1);
+    }
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let token = ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        settings.token_expire_seconds,
+        client_id,
+        settings.client_timeout_seconds,
+        server_addresses,
+        Some(&request.handshake.to_user_data()),
+        &settings.private_key.0,
+    )?;
+    token.write(&mut stream)?;
+    Ok(client_id)
+}
@@ -0,0 +1,250 @@
+//! A single simulated player, driving its own `RenetClient` without Bevy.
+
+use crate::{BotSettings, InputPattern, Rng};
+use bevy_renet::netcode::{ClientAuthentication, NetcodeClientTransport};
+use bevy_renet::renet::{ConnectionConfig, DefaultChannel, RenetClient};
+use protocol::auth::{TokenRequest, request_connect_token};
+use protocol::netsim;
+use protocol::snapshot::{DeltaSnapshot, SnapshotAck, SnapshotReceiver};
+use protocol::{ClientMessages, Handshake, InputCommand, PROTOCOL_ID, PlayerInput, ServerMessages};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime};
+
+/// How long a bot may take to connect before it counts as a failed attempt
+const CONNECT_TIMEOUT: f64 = 5.0;
+
+/// Why a bot stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Never got connected within the timeout, or the transport failed while connecting
+    Failed,
+    /// Refused by the server with `ServerMessages::ConnectionRejected`
+    Rejected,
+    /// Lost its connection after joining
+    Dropped,
+    /// Left on purpose, because of churn or the end of the run
+    Left,
+}
+
+pub struct Bot {
+    client_id: u64,
+    client: RenetClient,
+    transport: NetcodeClientTransport,
+    /// When the current connection attempt started
+    connecting_since: f64,
+    pub connected_at: Option<f64>,
+    /// Wait the server asked for in `ServerMessages::ServerShuttingDown`, applied once it disconnects the bot
+    shutdown_hint: Option<Duration>,
+    /// When to reconnect after the server the bot was on shut down
+    reconnect_at: Option<f64>,
+    /// Times the bot moved to another server, after a redirect or a shutdown
+    pub reconnects: u64,
+    /// When the bot leaves on its own, `None` to stay until the end of the run
+    leave_at: Option<f64>,
+    sequence: u32,
+    input: PlayerInput,
+    next_input_change: f64,
+    /// Offset into the circle pattern, so bots do not all walk in lockstep
+    phase: usize,
+    snapshots: SnapshotReceiver,
+    pub snapshots_received: u64,
+}
+
+impl Bot {
+    pub fn connect(client_id: u64, settings: &BotSettings, now: f64, leave_at: Option<f64>, phase: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            client_id,
+            client: RenetClient::new(ConnectionConfig::default()),
+            transport: open_transport(client_id, settings, settings.server_addr)?,
+            connecting_since: now,
+            connected_at: None,
+            shutdown_hint: None,
+            reconnect_at: None,
+            reconnects: 0,
+            leave_at,
+            sequence: 0,
+            input: PlayerInput::default(),
+            next_input_change: now,
+            phase,
+            snapshots: SnapshotReceiver::default(),
+            snapshots_received: 0,
+        })
+    }
+
+    /// Replace the connection with a new one to `server_addr`, keeping the bot's counters
+    fn reconnect(&mut self, settings: &BotSettings, server_addr: SocketAddr, now: f64) -> Result<(), Box<dyn Error>> {
+        self.transport = open_transport(self.client_id, settings, server_addr)?;
+        self.client = RenetClient::new(ConnectionConfig::default());
+        self.snapshots = SnapshotReceiver::default();
+        self.connecting_since = now;
+        self.reconnects += 1;
+        Ok(())
+    }
+
+    /// How the bot ended when its connection is gone for good
+    fn lost(&self) -> Outcome {
+        if self.connected_at.is_some() {
+            Outcome::Dropped
+        } else {
+            Outcome::Failed
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    /// Round trip time in seconds, as estimated by renet
+    pub fn rtt(&self) -> f64 {
+        self.client.rtt()
+    }
+
+    /// Run one tick: exchange packets, read messages and send input. Returns how the bot ended once it stops.
+    pub fn update(&mut self, delta: Duration, now: f64, settings: &BotSettings, rng: &mut Rng) -> Option<Outcome> {
+        if let Some(reconnect_at) = self.reconnect_at {
+            if now < reconnect_at {
+                // Already disconnected, nothing to tell the server
+                return self.leave_at.is_some_and(|leave_at| now >= leave_at).then_some(Outcome::Left);
+            }
+            self.reconnect_at = None;
+            // Through the token service when there is one, it lists the servers still up
+            if let Err(e) = self.reconnect(settings, settings.server_addr, now) {
+                eprintln!("Bot {} failed to reconnect after a shutdown: {}", self.client_id, e);
+                return Some(Outcome::Dropped);
+            }
+        }
+
+        self.client.update(delta);
+        if self.transport.update(delta, &mut self.client).is_err() || self.client.is_disconnected() {
+            if let Some(shutdown_hint) = self.shutdown_hint.take() {
+                self.reconnect_at = Some(now + shutdown_hint.as_secs_f64());
+                return None;
+            }
+            return Some(self.lost());
+        }
+
+        if self.client.is_connected() {
+            self.connected_at.get_or_insert(now);
+            while let Some(message) = self.client.receive_message(DefaultChannel::ReliableOrdered) {
+                match protocol::decode::<ServerMessages>(&message) {
+                    Ok(ServerMessages::ConnectionRejected { .. }) => {
+                        self.disconnect();
+                        return Some(Outcome::Rejected);
+                    }
+                    Ok(ServerMessages::Redirect { address }) => {
+                        self.disconnect();
+                        if let Err(e) = self.reconnect(settings, address, now) {
+                            eprintln!("Bot {} failed to follow a redirect to {}: {}", self.client_id, address, e);
+                            return Some(Outcome::Dropped);
+                        }
+                        return None;
+                    }
+                    // Keep playing while the server drains, `None` means not to come back
+                    Ok(ServerMessages::ServerShuttingDown { reconnect_hint }) => self.shutdown_hint = reconnect_hint,
+                    _ => {}
+                }
+            }
+            let mut ack = None;
+            while let Some(message) = self.client.receive_message(DefaultChannel::Unreliable) {
+                let Ok(snapshot) = DeltaSnapshot::from_bytes(&message) else {
+                    continue;
+                };
+                self.snapshots_received += 1;
+                if self.snapshots.receive(&snapshot).is_some() {
+                    ack = Some(SnapshotAck { tick: snapshot.tick });
+                }
+            }
+            if let Some(ack) = ack {
+                self.client
+                    .send_message(DefaultChannel::Unreliable, bincode::serialize(&ack).unwrap());
+            }
+            self.steer(now, settings.pattern, rng);
+            self.sequence = self.sequence.wrapping_add(1);
+            let command = InputCommand {
+                sequence: self.sequence,
+                input: self.input.clone(),
+            };
+            self.client.send_message(
+                DefaultChannel::ReliableOrdered,
+                bincode::serialize(&ClientMessages::Input { command }).unwrap(),
+            );
+        } else if now - self.connecting_since > CONNECT_TIMEOUT {
+            self.disconnect();
+            return Some(self.lost());
+        }
+
+        if self.leave_at.is_some_and(|leave_at| now >= leave_at) {
+            self.disconnect();
+            return Some(Outcome::Left);
+        }
+        // A send error shows up as a transport error on the next update
+        let _ = self.transport.send_packets(&mut self.client);
+        None
+    }
+
+    /// Pick the input to hold until the next change
+    fn steer(&mut self, now: f64, pattern: InputPattern, rng: &mut Rng) {
+        if now < self.next_input_change {
+            return;
+        }
+        let direction = match pattern {
+            InputPattern::Idle => None,
+            InputPattern::Random => {
+                self.next_input_change = now + 0.5 + rng.next_f64() * 1.5;
+                // One of the eight directions, or standing still
+                Some(rng.next_u64() as usize % 9).filter(|&direction| direction < 8)
+            }
+            InputPattern::Circle => {
+                self.next_input_change = now + 1.0;
+                self.phase = (self.phase + 1) % 8;
+                Some(self.phase)
+            }
+        };
+        // Directions go clockwise from up, in eighths of a turn
+        self.input = match direction {
+            Some(direction) => PlayerInput {
+                up: matches!(direction, 7 | 0 | 1),
+                right: matches!(direction, 1..=3),
+                down: matches!(direction, 3..=5),
+                left: matches!(direction, 5..=7),
+            },
+            None => PlayerInput::default(),
+        };
+    }
+
+    /// Tell the server right away instead of letting the connection time out
+    pub fn disconnect(&mut self) {
+        self.client.disconnect();
+        self.transport.disconnect();
+    }
+}
+
+/// Create a transport to `server_addr`, with a connect token from the token service if one is configured
+fn open_transport(client_id: u64, settings: &BotSettings, server_addr: SocketAddr) -> Result<NetcodeClientTransport, Box<dyn Error>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let handshake = Handshake::current();
+    let authentication = match &settings.auth_addr {
+        Some(auth_addr) => ClientAuthentication::Secure {
+            connect_token: request_connect_token(
+                auth_addr.as_str(),
+                &TokenRequest {
+                    handshake,
+                    preferred_server: Some(server_addr),
+                },
+            )?,
+        },
+        None => ClientAuthentication::Unsecure {
+            client_id,
+            protocol_id: PROTOCOL_ID,
+            server_addr,
+            user_data: Some(handshake.to_user_data()),
+        },
+    };
+    let authentication = match settings.network_conditions {
+        Some(conditions) => netsim::route_client(authentication, conditions)?,
+        None => authentication,
+    };
+    Ok(NetcodeClientTransport::new(current_time, authentication, socket)?)
+}
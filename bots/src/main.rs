@@ -0,0 +1,277 @@
+//! Headless load tester, runs many simulated players against one server from a single process.
+
+mod bot;
+#[cfg(test)]
+mod tests;
+
+use bot::{Bot, Outcome};
+use protocol::netsim::NetworkConditions;
+use std::env;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How the bots steer their players
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputPattern {
+    /// Hold a random direction, or stand still, for half a second to two seconds
+    Random,
+    /// Turn an eighth of a circle every second
+    Circle,
+    /// Send empty input every tick
+    Idle,
+}
+
+impl InputPattern {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "random" => Some(Self::Random),
+            "circle" => Some(Self::Circle),
+            "idle" => Some(Self::Idle),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BotSettings {
+    pub server_addr: SocketAddr,
+    /// Token service to ask for connect tokens, bots connect unsecured without one
+    pub auth_addr: Option<String>,
+    /// Latency, jitter, loss, reordering and duplication to simulate on every bot's connection
+    pub network_conditions: Option<NetworkConditions>,
+    /// How many bots are connected at once
+    bots: usize,
+    duration: Duration,
+    /// Pause between two bots joining, so the server is not hit by every handshake at once
+    join_interval: Duration,
+    /// How long a bot stays before it leaves and a new one joins in its place, `None` for no churn
+    lifetime: Option<f64>,
+    pattern: InputPattern,
+    /// Rate the bots send input at, should match the server's tick rate
+    tick_rate: f64,
+    seed: u64,
+    /// Bots use consecutive client ids from here
+    client_id_base: u64,
+}
+
+impl Default for BotSettings {
+    fn default() -> Self {
+        let server_ip = env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let server_port = env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string());
+        let unix_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        Self {
+            server_addr: format!("{}:{}", server_ip, server_port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .expect("SERVER_IP and SERVER_PORT must resolve to an address"),
+            auth_addr: env::var("AUTH_ADDR").ok(),
+            network_conditions: NetworkConditions::from_env(),
+            bots: env::var("BOTS").ok().and_then(|s| s.parse().ok()).unwrap_or(16),
+            duration: Duration::from_secs_f64(env::var("DURATION_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(60.0)),
+            join_interval: Duration::from_millis(env::var("JOIN_INTERVAL_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(50)),
+            lifetime: env::var("BOT_LIFETIME_SECONDS").ok().and_then(|s| s.parse().ok()),
+            pattern: env::var("INPUT_PATTERN")
+                .ok()
+                .and_then(|s| InputPattern::from_name(&s))
+                .unwrap_or(InputPattern::Random),
+            tick_rate: env::var("TICK_RATE").ok().and_then(|s| s.parse().ok()).unwrap_or(60.0),
+            seed: env::var("SEED")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(unix_time.as_nanos() as u64),
+            // Fresh ids every run, so bots do not collide with players the server still holds from the last one
+            client_id_base: env::var("CLIENT_ID_BASE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(unix_time.as_secs() * 1000),
+        }
+    }
+}
+
+/// Small xorshift generator, the same seed replays the same inputs and churn
+pub struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Everything measured over a run
+#[derive(Debug, Default)]
+struct Report {
+    attempts: u64,
+    connected: u64,
+    failed: u64,
+    rejected: u64,
+    /// Connections lost after joining, the server timing bots out counts here
+    dropped: u64,
+    /// Bots that followed a redirect or came back after a server shut down
+    reconnects: u64,
+    snapshots: u64,
+    /// Sum of the time every bot spent connected
+    connected_seconds: f64,
+    /// Round trip times in seconds, sampled once per second from every connected bot
+    rtt_samples: Vec<f64>,
+}
+
+impl Report {
+    fn success_rate(&self) -> f64 {
+        self.connected as f64 / self.attempts.max(1) as f64
+    }
+
+    /// Snapshots received per second by a connected bot
+    fn snapshot_rate(&self) -> f64 {
+        if self.connected_seconds > 0.0 {
+            self.snapshots as f64 / self.connected_seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Nearest rank percentile of the round trip times in milliseconds
+    fn rtt_percentile(&self, percentile: f64) -> Option<f64> {
+        let mut samples = self.rtt_samples.clone();
+        samples.sort_by(f64::total_cmp);
+        let rank = (percentile / 100.0 * samples.len() as f64).ceil() as usize;
+        samples.get(rank.saturating_sub(1)).map(|rtt| rtt * 1000.0)
+    }
+
+    /// Count a bot that stopped, `now` is when it did
+    fn finish(&mut self, bot: &Bot, outcome: Outcome, now: f64) {
+        match outcome {
+            Outcome::Failed => self.failed += 1,
+            Outcome::Rejected => self.rejected += 1,
+            Outcome::Dropped => self.dropped += 1,
+            Outcome::Left => {}
+        }
+        self.reconnects += bot.reconnects;
+        self.snapshots += bot.snapshots_received;
+        if let Some(connected_at) = bot.connected_at {
+            self.connected_seconds += now - connected_at;
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Connect attempts:   {}", self.attempts)?;
+        writeln!(f, "Connected:          {} ({:.1}%)", self.connected, self.success_rate() * 100.0)?;
+        writeln!(f, "Failed to connect:  {}", self.failed)?;
+        writeln!(f, "Rejected:           {}", self.rejected)?;
+        writeln!(f, "Dropped:            {}", self.dropped)?;
+        writeln!(f, "Reconnects:         {}", self.reconnects)?;
+        writeln!(f, "Snapshot rate:      {:.1}/s per bot", self.snapshot_rate())?;
+        match (self.rtt_percentile(50.0), self.rtt_percentile(90.0), self.rtt_percentile(99.0)) {
+            (Some(p50), Some(p90), Some(p99)) => write!(f, "RTT p50/p90/p99:    {:.1}/{:.1}/{:.1} ms", p50, p90, p99),
+            _ => write!(f, "RTT p50/p90/p99:    no samples"),
+        }
+    }
+}
+
+/// Run the bots until the duration is up, then print the report
+fn main() {
+    let settings = BotSettings::default();
+    println!("{:?}", settings);
+    let report = run(&settings);
+    println!("{}", report);
+}
+
+fn run(settings: &BotSettings) -> Report {
+    let mut rng = Rng::new(settings.seed);
+    let mut report = Report::default();
+    let mut bots: Vec<Bot> = Vec::new();
+    // Bots still to connect, leaving bots put their slot back
+    let mut pending = settings.bots;
+    let mut next_join = 0.0;
+    let mut next_rtt_sample = 1.0;
+    let tick = Duration::from_secs_f64(1.0 / settings.tick_rate);
+    let start = Instant::now();
+    let mut last_update = start;
+
+    loop {
+        let now = start.elapsed().as_secs_f64();
+        if now >= settings.duration.as_secs_f64() {
+            break;
+        }
+        let delta = last_update.elapsed();
+        last_update = Instant::now();
+
+        if pending > 0 && now >= next_join {
+            pending -= 1;
+            next_join = now + settings.join_interval.as_secs_f64();
+            let client_id = settings.client_id_base + report.attempts;
+            report.attempts += 1;
+            // Spread lifetimes so churned bots do not all leave on the same tick
+            let leave_at = settings.lifetime.map(|lifetime| now + lifetime * (0.5 + rng.next_f64()));
+            match Bot::connect(client_id, settings, now, leave_at, report.attempts as usize) {
+                Ok(bot) => bots.push(bot),
+                Err(e) => {
+                    eprintln!("Bot {} failed to start: {}", client_id, e);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        let sample_rtt = now >= next_rtt_sample;
+        if sample_rtt {
+            next_rtt_sample += 1.0;
+        }
+        let mut index = 0;
+        while index < bots.len() {
+            let was_connected = bots[index].connected_at.is_some();
+            let outcome = bots[index].update(delta, now, settings, &mut rng);
+            let bot = &bots[index];
+            if !was_connected && bot.connected_at.is_some() {
+                report.connected += 1;
+            }
+            match outcome {
+                Some(outcome) => {
+                    report.finish(bot, outcome, now);
+                    if outcome == Outcome::Left {
+                        pending += 1;
+                    }
+                    bots.swap_remove(index);
+                }
+                None => {
+                    if sample_rtt && bot.is_connected() {
+                        report.rtt_samples.push(bot.rtt());
+                    }
+                    index += 1;
+                }
+            }
+        }
+
+        if let Some(remaining) = tick.checked_sub(last_update.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+
+    let now = start.elapsed().as_secs_f64();
+    for mut bot in bots {
+        if bot.connected_at.is_some() {
+            report.finish(&bot, Outcome::Left, now);
+        } else {
+            // Still connecting for the first time when the run ended
+            report.finish(&bot, Outcome::Failed, now);
+        }
+        bot.disconnect();
+    }
+    report
+}
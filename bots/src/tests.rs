@@ -0,0 +1,51 @@
+//! Tests of the report math and input generation, no server needed.
+
+use super::*;
+
+#[test]
+fn rtt_percentiles_use_nearest_rank() {
+    let report = Report {
+        rtt_samples: (1..=100).rev().map(|ms| ms as f64 / 1000.0).collect(),
+        ..Default::default()
+    };
+    assert_eq!(report.rtt_percentile(50.0).unwrap().round(), 50.0);
+    assert_eq!(report.rtt_percentile(90.0).unwrap().round(), 90.0);
+    assert_eq!(report.rtt_percentile(99.0).unwrap().round(), 99.0);
+    assert_eq!(Report::default().rtt_percentile(50.0), None);
+}
+
+#[test]
+fn rates_survive_empty_runs() {
+    let report = Report::default();
+    assert_eq!(report.success_rate(), 0.0);
+    assert_eq!(report.snapshot_rate(), 0.0);
+
+    let report = Report {
+        attempts: 4,
+        connected: 3,
+        snapshots: 600,
+        connected_seconds: 10.0,
+        ..Default::default()
+    };
+    assert_eq!(report.success_rate(), 0.75);
+    assert_eq!(report.snapshot_rate(), 60.0);
+}
+
+#[test]
+fn same_seed_replays_the_same_numbers() {
+    let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+    for _ in 0..100 {
+        let value = a.next_f64();
+        assert_eq!(value, b.next_f64());
+        assert!((0.0..1.0).contains(&value));
+    }
+    // A zero seed would otherwise stay zero forever
+    assert_ne!(Rng::new(0).next_u64(), 0);
+}
+
+#[test]
+fn input_patterns_parse_by_name() {
+    assert_eq!(InputPattern::from_name("Circle"), Some(InputPattern::Circle));
+    assert_eq!(InputPattern::from_name("idle"), Some(InputPattern::Idle));
+    assert_eq!(InputPattern::from_name("zigzag"), None);
+}
@@ -1,73 +1,25 @@
-use once_cell::sync::Lazy;
 use std::env;
-use std::time::SystemTime;
 
+use bevy::prelude::*;
 use bevy::render::texture::ImagePlugin;
 use bevy::sprite::{Sprite, TextureAtlas};
-use bevy::{app::AppExit, prelude::*};
-use bevy_renet::netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport, NetcodeTransportError};
-use bevy_renet::renet::{ClientId, ConnectionConfig, DefaultChannel, RenetClient};
-use bevy_renet::{RenetClientPlugin, client_connected};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, net::UdpSocket};
-use std::{thread, time::Duration};
-
-const PROTOCOL_ID: u64 = 7;
-
-pub static CLIENT_ID: Lazy<u64> = Lazy::new(|| {
-    env::var("CLIENT_ID")
-        .ok()
-        .and_then(|id_str| id_str.parse().ok())
-        .unwrap_or_else(|| {
-            let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-            current_time.as_millis() as u64
-        })
-});
-
-// Updated ClientSettings with additional fields.
-#[derive(Resource, Clone)]
-struct ClientSettings {
-    max_retries: u32,
-    initial_delay: Duration,
-    server_ip: String,
-    server_port: String,
-    sprite_size: Vec2,
-}
-
-impl Default for ClientSettings {
-    fn default() -> Self {
-        Self {
-            max_retries: 10,
-            initial_delay: Duration::from_secs(1),
-            server_ip: env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string()),
-            sprite_size: Vec2::new(64.0, 64.0),
-        }
-    }
-}
-
-#[derive(Debug, Default, Serialize, Deserialize, Component, Resource, Clone)]
-struct PlayerInput {
-    up: bool,
-    down: bool,
-    left: bool,
-    right: bool,
-}
-
-#[derive(Debug, Default, Resource)]
-struct Lobby {
-    players: HashMap<ClientId, Entity>,
-}
-
-#[derive(Debug, Serialize, Deserialize, Component)]
-enum ServerMessages {
-    PlayerConnected { id: ClientId, color: [f32; 4] },
-    PlayerDisconnected { id: ClientId },
+use bevy_renet::renet::ClientId;
+use client_common::{
+    ClientSettings, LocalPlayer, NetworkPlayer, NetworkPlugin, NetworkSync, PlayerColor, PlayerName, PlayerPosition, exit_system,
+    player_input,
+};
+use protocol::{Lobby, PlayerInput};
+use std::time::Duration;
+
+/// Size every player sprite is drawn at, before the player transform's scale
+const SPRITE_SIZE: Vec2 = Vec2::new(64.0, 64.0);
+
+/// Text following a player around
+#[derive(Component)]
+struct NameLabel {
+    player: Entity,
 }
 
-#[derive(Resource, Default)]
-struct InitialSyncDone(bool);
-
 // New components for animation
 #[derive(Component)]
 struct AnimationIndices {
@@ -159,10 +111,6 @@ fn animate_sprite(time: Res<Time>, mut query: Query<(&AnimationIndices, &mut Ani
     }
 }
 
-// Composite system to update AnimationIndices based on player input and LastDirection.
-#[derive(Component)]
-struct LocalPlayer;
-
 fn update_direction_and_indices(
     player_input: Res<PlayerInput>,
     mut last_direction: ResMut<LastDirection>,
@@ -254,7 +202,6 @@ fn update_remote_player_animation(
 
 /// Run bevy client
 fn main() {
-    let client_settings = ClientSettings::default();
     let multiplayer = env::var("MULTIPLAYER").unwrap_or_default().to_lowercase() == "true";
 
     let mut app = App::new();
@@ -265,30 +212,20 @@ fn main() {
     )
     .init_resource::<Lobby>()
     .init_resource::<PlayerInput>()
-    .init_resource::<InitialSyncDone>()
     .init_resource::<LastDirection>() // initialize LastDirection.
-    .insert_resource(client_settings.clone())
+    .insert_resource(ClientSettings::default())
     .insert_resource(AnimationConfig::default()); // add AnimationConfig
 
     // Register setup and animation system.
     if multiplayer {
-        // Multiplayer: initialize renet client and add network plugins/systems.
-        let (renet_client, renet_transport) = new_renet_client(&client_settings);
-        app.insert_resource(renet_client)
-            .insert_resource(renet_transport)
-            .add_plugins((RenetClientPlugin, NetcodeClientPlugin))
+        // Multiplayer: connect, then draw the players the server tells us about.
+        app.add_plugins(NetworkPlugin)
             .add_systems(Startup, setup)
-            .add_systems(Update, animate_sprite)
-            .add_systems(
-                Update,
-                (player_input, client_send_input, client_sync_players, update_direction_and_indices).run_if(client_connected),
-            )
+            .add_systems(Update, (animate_sprite, player_input, exit_system, update_direction_and_indices))
+            .add_systems(Update, (draw_players_system, place_players_system).chain().after(NetworkSync))
+            .add_systems(Update, name_label_system.after(place_players_system))
             // NEW: update remote players animation (only those without LocalPlayer)
-            .add_systems(Update, update_remote_player_animation.run_if(client_connected))
-            .add_systems(
-                Update,
-                (network_error_reconnect_system, periodic_connection_checker_system, exit_system),
-            );
+            .add_systems(Update, update_remote_player_animation.after(place_players_system));
     } else {
         // Local mode: spawn local player, update input, then move the player.
         app.add_systems(Startup, setup)
@@ -309,119 +246,68 @@ fn main() {
     app.run();
 }
 
-/// Create a new RenetClient and NetcodeClientTransport using settings from ClientSettings
-fn new_renet_client(settings: &ClientSettings) -> (RenetClient, NetcodeClientTransport) {
-    let server_addr = format!("{}:{}", settings.server_ip, settings.server_port).parse().unwrap();
-
-    info!("Connecting to server at: {}", server_addr);
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    let client_id = *CLIENT_ID;
-    info!("Using CLIENT_ID={}", client_id);
-
-    let authentication = ClientAuthentication::Unsecure {
-        client_id,
-        protocol_id: PROTOCOL_ID,
-        server_addr,
-        user_data: None,
-    };
-
-    let mut retries = 0;
-    let mut delay = settings.initial_delay;
-
-    while retries < settings.max_retries {
-        match NetcodeClientTransport::new(current_time, authentication.clone(), socket.try_clone().unwrap()) {
-            Ok(transport) => {
-                println!("✅ Connected to server on attempt {}", retries + 1);
-                let client = RenetClient::new(ConnectionConfig::default());
-                return (client, transport);
-            }
-            Err(e) => {
-                println!(
-                    "⚠️ Connection attempt {} failed: {}. Retrying in {}s...",
-                    retries + 1,
-                    e,
-                    delay.as_secs()
-                );
-                thread::sleep(delay);
-                delay *= 2; // Exponential backoff (1s, 2s, 4s, 8s...)
-                retries += 1;
-            }
-        }
+/// Give every player the server told us about an animated sprite, tinted with the color the server assigned it
+fn draw_players_system(
+    mut commands: Commands,
+    player_asset: Res<PlayerAsset>,
+    anim_config: Res<AnimationConfig>,
+    added: Query<(Entity, &PlayerColor), Added<NetworkPlayer>>,
+    mut recolored: Query<(&PlayerColor, &mut Sprite), Changed<PlayerColor>>,
+) {
+    for (player_entity, &PlayerColor(color)) in added.iter() {
+        let mut sprite = create_sprite(&player_asset, 1);
+        sprite.color = player_tint(color);
+        let transform = default_player_transform();
+        let (animation_indices, anim_timer) = create_animation_components(&anim_config);
+        commands.entity(player_entity).insert((
+            sprite,
+            transform,
+            animation_indices,
+            anim_timer,
+            PreviousTransform(transform.translation),
+        ));
+    }
+    for (&PlayerColor(color), mut sprite) in recolored.iter_mut() {
+        sprite.color = player_tint(color);
     }
+}
 
-    panic!("❌ Failed to connect to server after {} attempts.", settings.max_retries);
+/// Move every sprite to where its player is, the server's ground plane is the screen
+fn place_players_system(mut query: Query<(&PlayerPosition, &mut Transform), Changed<PlayerPosition>>) {
+    for (position, mut transform) in query.iter_mut() {
+        transform.translation = Vec3::new(position.0.x, -position.0.z, 0.0);
+    }
 }
 
-/// Sync player with the server
-fn client_sync_players(
+/// Keep a label with its name above every named player, and remove labels of players that are gone
+fn name_label_system(
     mut commands: Commands,
-    mut client: ResMut<RenetClient>,
-    mut lobby: ResMut<Lobby>,
-    mut initial_sync: ResMut<InitialSyncDone>,
-    player_asset: Res<PlayerAsset>,
-    settings: Res<ClientSettings>,
-    anim_config: Res<AnimationConfig>,
+    named: Query<(Entity, &PlayerName), Added<PlayerName>>,
+    players: Query<(Ref<PlayerName>, &Transform), Without<NameLabel>>,
+    mut labels: Query<(Entity, &NameLabel, &mut Text2d, &mut Transform)>,
 ) {
-    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
-        let server_message: ServerMessages = bincode::deserialize(&message).unwrap();
-        match server_message {
-            ServerMessages::PlayerConnected { id, color: _ } => {
-                let sprite = create_sprite(&player_asset, &settings, 1);
-                let transform = default_player_transform();
-                let (animation_indices, anim_timer) = create_animation_components(&anim_config);
-                // Spawn remote player with a PreviousTransform.
-                let bundle = (
-                    sprite,
-                    transform,
-                    animation_indices,
-                    anim_timer,
-                    PreviousTransform(transform.translation),
-                );
-                let player_entity = commands.spawn(bundle).id();
-                if id == *CLIENT_ID {
-                    commands.entity(player_entity).insert(LocalPlayer);
-                }
-                lobby.players.insert(id, player_entity);
-            }
-            ServerMessages::PlayerDisconnected { id } => {
-                info!("Player {} disconnected.", id);
-                if let Some(player_entity) = lobby.players.remove(&id) {
-                    commands.entity(player_entity).despawn();
-                }
-            }
-        }
+    for (player, name) in named.iter() {
+        commands.spawn((
+            Text2d::new(name.0.clone()),
+            TextFont {
+                font_size: 16.0,
+                ..default()
+            },
+            Transform::default(),
+            NameLabel { player },
+        ));
     }
-
-    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-        let players: HashMap<ClientId, ([f32; 3], [f32; 4])> = bincode::deserialize(&message).unwrap();
-        for (player_id, (translation, _color)) in players.iter() {
-            let new_translation = Vec3::new(translation[0], -translation[2], 0.0);
-            if let Some(&player_entity) = lobby.players.get(player_id) {
-                commands.entity(player_entity).insert(Transform {
-                    translation: new_translation,
-                    ..Default::default()
-                });
-            } else if !initial_sync.0 {
-                let sprite = create_sprite(&player_asset, &settings, 1);
-                let (animation_indices, anim_timer) = create_animation_components(&anim_config);
-                let transform = Transform {
-                    translation: new_translation,
-                    ..default_player_transform()
-                };
-                let player_entity = commands
-                    .spawn((
-                        sprite,
-                        transform,
-                        animation_indices,
-                        anim_timer,
-                        PreviousTransform(transform.translation),
-                    ))
-                    .id();
-                lobby.players.insert(*player_id, player_entity);
-            }
+    for (label, name_label, mut text, mut transform) in labels.iter_mut() {
+        let Ok((name, player_transform)) = players.get(name_label.player) else {
+            commands.entity(label).despawn();
+            continue;
+        };
+        if name.is_changed() {
+            text.0 = name.0.clone();
         }
-        initial_sync.0 = true;
+        // Just above the sprite, drawn over every player
+        let above = SPRITE_SIZE.y * player_transform.scale.y / 2.0 + 10.0;
+        transform.translation = player_transform.translation + Vec3::new(0.0, above, 1.0);
     }
 }
 
@@ -439,79 +325,6 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>, mut texture_atl
     });
 }
 
-/// Update the player input
-fn player_input(keyboard_input: Res<ButtonInput<KeyCode>>, mut player_input: ResMut<PlayerInput>) {
-    player_input.left = keyboard_input.pressed(KeyCode::KeyA) || keyboard_input.pressed(KeyCode::ArrowLeft);
-    player_input.right = keyboard_input.pressed(KeyCode::KeyD) || keyboard_input.pressed(KeyCode::ArrowRight);
-    player_input.up = keyboard_input.pressed(KeyCode::KeyW) || keyboard_input.pressed(KeyCode::ArrowUp);
-    player_input.down = keyboard_input.pressed(KeyCode::KeyS) || keyboard_input.pressed(KeyCode::ArrowDown);
-}
-
-/// Exit system that gracefully disconnects from renet on Escape key press
-fn exit_system(keyboard_input: Res<ButtonInput<KeyCode>>, client: Option<ResMut<RenetClient>>, mut exit: EventWriter<AppExit>) {
-    if keyboard_input.just_pressed(KeyCode::Escape) {
-        println!("Exit requested. Disconnecting gracefully...");
-        if let Some(mut client) = client {
-            client.disconnect();
-        }
-        exit.send(AppExit::Success);
-    }
-}
-
-/// Send the player input to the server
-fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>) {
-    let input_message = bincode::serialize(&*player_input).unwrap();
-    client.send_message(DefaultChannel::ReliableOrdered, input_message);
-}
-
-/// Attempts to perform a full reconnection by removing outdated networking resources
-/// and inserting new client and transport resources using default client settings.
-fn perform_reconnect(commands: &mut Commands) {
-    // Remove existing networking resources.
-    commands.remove_resource::<RenetClient>();
-    commands.remove_resource::<NetcodeClientTransport>();
-    // Create and insert new networking resources.
-    let (new_client, new_transport) = new_renet_client(&ClientSettings::default());
-    commands.insert_resource(new_client);
-    commands.insert_resource(new_transport);
-    println!("✅ Successfully reconnected to the server.");
-}
-
-/// System that listens for network transport errors and attempts a reconnection when one is detected.
-/// It triggers at most once per second to avoid repeated reconnection attempts.
-#[allow(clippy::never_loop)]
-fn network_error_reconnect_system(
-    mut commands: Commands,
-    mut transport_errors: EventReader<NetcodeTransportError>,
-    time: Res<Time>,
-    mut last_attempt: Local<f64>,
-) {
-    if time.elapsed_secs_f64() - *last_attempt < 1.0 {
-        return;
-    }
-    *last_attempt = time.elapsed_secs_f64();
-
-    for error in transport_errors.read() {
-        error!("⚠️ Network transport error detected: {:?}", error);
-        println!("🔄 Initiating reconnection due to network error...");
-        perform_reconnect(&mut commands);
-    }
-}
-
-/// Periodically checks the client's connection status and triggers a reconnection if not connected.
-/// This system limits reconnection attempts to at most once per second.
-fn periodic_connection_checker_system(mut commands: Commands, client: Res<RenetClient>, time: Res<Time>, mut last_check: Local<f64>) {
-    if time.elapsed_secs_f64() - *last_check < 1.0 {
-        return;
-    }
-    *last_check = time.elapsed_secs_f64();
-
-    if !client.is_connected() {
-        println!("⚠️ Connection lost. Initiating periodic reconnection check...");
-        perform_reconnect(&mut commands);
-    }
-}
-
 /// For local simulation, add a simple system that updates transformations using local input
 fn local_move_players_system(mut query: Query<(&mut Transform, &PlayerInput)>, time: Res<Time>) {
     for (mut transform, input) in query.iter_mut() {
@@ -526,7 +339,7 @@ fn local_move_players_system(mut query: Query<(&mut Transform, &PlayerInput)>, t
 fn local_spawn_player(mut commands: Commands, mut lobby: ResMut<Lobby>, player_asset: Res<PlayerAsset>, anim_config: Res<AnimationConfig>) {
     if lobby.players.is_empty() {
         let local_client_id: ClientId = 0;
-        let sprite = create_sprite(&player_asset, &ClientSettings::default(), 1);
+        let sprite = create_sprite(&player_asset, 1);
         let (animation_indices, anim_timer) = create_animation_components(&anim_config);
         let bundle = (
             sprite,
@@ -544,10 +357,10 @@ fn local_spawn_player(mut commands: Commands, mut lobby: ResMut<Lobby>, player_a
 
 /// Update local player's PlayerInput component from the global resource
 fn local_update_player_input(player_input_res: Res<PlayerInput>, lobby: Res<Lobby>, mut query: Query<&mut PlayerInput>) {
-    if let Some(&local_entity) = lobby.players.get(&0) {
-        if let Ok(mut component) = query.get_mut(local_entity) {
-            *component = player_input_res.clone();
-        }
+    if let Some(&local_entity) = lobby.players.get(&0)
+        && let Ok(mut component) = query.get_mut(local_entity)
+    {
+        *component = player_input_res.clone();
     }
 }
 
@@ -557,7 +370,7 @@ fn default_player_transform() -> Transform {
 }
 
 /// Creates a Sprite from the player asset with the given starting index and applies the sprite size.
-fn create_sprite(player_asset: &PlayerAsset, settings: &ClientSettings, index: usize) -> Sprite {
+fn create_sprite(player_asset: &PlayerAsset, index: usize) -> Sprite {
     let mut sprite = Sprite::from_atlas_image(
         player_asset.texture.clone(),
         TextureAtlas {
@@ -565,10 +378,15 @@ fn create_sprite(player_asset: &PlayerAsset, settings: &ClientSettings, index: u
             index,
         },
     );
-    sprite.custom_size = Some(settings.sprite_size);
+    sprite.custom_size = Some(SPRITE_SIZE);
     sprite
 }
 
+/// Tint for a player's sprite from the linear RGBA color the server assigned, the same color the 3D client gives its cube
+fn player_tint(color: [f32; 4]) -> Color {
+    Color::linear_rgba(color[0], color[1], color[2], color[3])
+}
+
 /// Creates default animation components using the idle_right configuration.
 fn create_animation_components(anim_config: &AnimationConfig) -> (AnimationIndices, AnimationTimer) {
     let (first, last_val) = anim_config.idle_right;
@@ -0,0 +1,213 @@
+//! Finding a server, connecting to it, and reconnecting when the connection is lost.
+
+use crate::{ClientSettings, ServerShutdown};
+use bevy::prelude::*;
+use bevy_renet::netcode::{ClientAuthentication, NetcodeClientTransport, NetcodeTransportError};
+use bevy_renet::renet::{ConnectionConfig, RenetClient};
+use once_cell::sync::Lazy;
+use protocol::auth::{TokenRequest, request_connect_token};
+use protocol::directory::find_server;
+use protocol::netsim;
+use protocol::session::SessionToken;
+use protocol::{Handshake, PROTOCOL_ID, ProtocolVersion};
+use std::env;
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::SystemTime;
+use std::{thread, time::Duration};
+
+pub static CLIENT_ID: Lazy<u64> = Lazy::new(|| {
+    env::var("CLIENT_ID")
+        .ok()
+        .and_then(|id_str| id_str.parse().ok())
+        .unwrap_or_else(|| {
+            let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            current_time.as_millis() as u64
+        })
+});
+
+/// Create a new RenetClient and NetcodeClientTransport using settings from ClientSettings.
+/// With `take_over` the server hands this client its session's player even if the old connection is still live.
+pub fn new_renet_client(settings: &ClientSettings, take_over: bool) -> (RenetClient, NetcodeClientTransport) {
+    let server_addr: SocketAddr = format!("{}:{}", settings.server_ip, settings.server_port).parse().unwrap();
+
+    info!("Connecting to server at: {}", server_addr);
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let mut retries = 0;
+    let mut delay = settings.initial_delay;
+
+    while retries < settings.max_retries {
+        match connect_transport(settings, take_over, server_addr, current_time, socket.try_clone().unwrap()) {
+            Ok(transport) => {
+                println!("✅ Connected to server on attempt {}", retries + 1);
+                info!("Using client id {} with protocol {}", transport.client_id(), Handshake::current());
+                let client = RenetClient::new(ConnectionConfig::default());
+                return (client, transport);
+            }
+            Err(e) => {
+                println!(
+                    "⚠️ Connection attempt {} failed: {}. Retrying in {}s...",
+                    retries + 1,
+                    e,
+                    delay.as_secs()
+                );
+                thread::sleep(delay);
+                delay *= 2; // Exponential backoff (1s, 2s, 4s, 8s...)
+                retries += 1;
+            }
+        }
+    }
+
+    panic!("❌ Failed to connect to server after {} attempts.", settings.max_retries);
+}
+
+/// Ask the directory for the least loaded compatible server, keeps the configured server if it has none
+pub fn pick_server(settings: &mut ClientSettings) {
+    let Some(directory_addr) = &settings.directory_addr else {
+        return;
+    };
+    match find_server(directory_addr.as_str(), ProtocolVersion::current()) {
+        Ok(Some(server)) => {
+            println!(
+                "🧭 Directory picked {} with {}/{} players",
+                server.address, server.players, server.capacity
+            );
+            settings.server_ip = server.address.ip().to_string();
+            settings.server_port = server.address.port().to_string();
+        }
+        Ok(None) => println!(
+            "⚠️ Directory has no server with room for protocol {}, trying {}:{}",
+            ProtocolVersion::current(),
+            settings.server_ip,
+            settings.server_port
+        ),
+        Err(e) => println!(
+            "⚠️ Directory at {} unavailable: {}, trying {}:{}",
+            directory_addr, e, settings.server_ip, settings.server_port
+        ),
+    }
+}
+
+/// Create the transport, with a connect token from the token service if one is configured
+fn connect_transport(
+    settings: &ClientSettings,
+    take_over: bool,
+    server_addr: SocketAddr,
+    current_time: Duration,
+    socket: UdpSocket,
+) -> Result<NetcodeClientTransport, Box<dyn Error>> {
+    let handshake = Handshake {
+        session: SessionToken::load(&settings.session_file),
+        palette: settings.palette,
+        take_over,
+        ..Handshake::current()
+    };
+    let authentication = match &settings.auth_addr {
+        Some(auth_addr) => ClientAuthentication::Secure {
+            connect_token: request_connect_token(
+                auth_addr.as_str(),
+                &TokenRequest {
+                    handshake,
+                    preferred_server: Some(server_addr),
+                },
+            )?,
+        },
+        None => ClientAuthentication::Unsecure {
+            client_id: *CLIENT_ID,
+            protocol_id: PROTOCOL_ID,
+            server_addr,
+            user_data: Some(handshake.to_user_data()),
+        },
+    };
+    let authentication = match settings.network_conditions {
+        Some(conditions) => {
+            println!("🐢 Simulating network conditions {:?}", conditions);
+            netsim::route_client(authentication, conditions)?
+        }
+        None => authentication,
+    };
+    Ok(NetcodeClientTransport::new(current_time, authentication, socket)?)
+}
+
+/// Attempts to perform a full reconnection by removing outdated networking resources
+/// and inserting new client and transport resources using the current client settings.
+fn perform_reconnect(commands: &mut Commands, settings: &ClientSettings) {
+    // Remove existing networking resources.
+    commands.remove_resource::<RenetClient>();
+    commands.remove_resource::<NetcodeClientTransport>();
+    // Create and insert new networking resources. The server may not have noticed yet that the old connection is gone.
+    let (new_client, new_transport) = new_renet_client(settings, true);
+    commands.insert_resource(new_client);
+    commands.insert_resource(new_transport);
+    println!("✅ Successfully reconnected to the server.");
+}
+
+/// Whether to reconnect now. After the server said it is shutting down, waits until its reconnect hint has passed
+/// since it disconnected us, then asks the directory for another server.
+fn ready_to_reconnect(commands: &mut Commands, shutdown: Option<ResMut<ServerShutdown>>, settings: &mut ClientSettings, now: f64) -> bool {
+    let Some(mut shutdown) = shutdown else {
+        return true;
+    };
+    let disconnected_at = *shutdown.disconnected_at.get_or_insert(now);
+    let Some(reconnect_hint) = shutdown.reconnect_hint else {
+        return false;
+    };
+    if now - disconnected_at < reconnect_hint.as_secs_f64() {
+        return false;
+    }
+    commands.remove_resource::<ServerShutdown>();
+    // Without a directory the token service, or the address we were given, has to lead somewhere else
+    pick_server(settings);
+    println!(
+        "🔄 Server we were on shut down, reconnecting to {}:{}",
+        settings.server_ip, settings.server_port
+    );
+    true
+}
+
+/// System that listens for network transport errors and attempts a reconnection when one is detected.
+/// It triggers at most once per second to avoid repeated reconnection attempts.
+pub fn network_error_reconnect_system(
+    mut commands: Commands,
+    mut transport_errors: EventReader<NetcodeTransportError>,
+    mut settings: ResMut<ClientSettings>,
+    shutdown: Option<ResMut<ServerShutdown>>,
+    time: Res<Time>,
+    mut last_attempt: Local<f64>,
+) {
+    if time.elapsed_secs_f64() - *last_attempt < 1.0 {
+        return;
+    }
+    *last_attempt = time.elapsed_secs_f64();
+
+    let Some(error) = transport_errors.read().last() else {
+        return;
+    };
+    error!("⚠️ Network transport error detected: {:?}", error);
+    if ready_to_reconnect(&mut commands, shutdown, &mut settings, time.elapsed_secs_f64()) {
+        println!("🔄 Initiating reconnection due to network error...");
+        perform_reconnect(&mut commands, &settings);
+    }
+}
+
+/// Periodically checks the client's connection status and triggers a reconnection if not connected.
+/// This system limits reconnection attempts to at most once per second.
+pub fn periodic_connection_checker_system(
+    mut commands: Commands,
+    client: Res<RenetClient>,
+    mut settings: ResMut<ClientSettings>,
+    shutdown: Option<ResMut<ServerShutdown>>,
+    time: Res<Time>,
+    mut last_check: Local<f64>,
+) {
+    if time.elapsed_secs_f64() - *last_check < 1.0 {
+        return;
+    }
+    *last_check = time.elapsed_secs_f64();
+
+    if !client.is_connected() && ready_to_reconnect(&mut commands, shutdown, &mut settings, time.elapsed_secs_f64()) {
+        println!("⚠️ Connection lost. Initiating periodic reconnection check...");
+        perform_reconnect(&mut commands, &settings);
+    }
+}
@@ -0,0 +1,211 @@
+//! Everything the 3D and 2D clients do the same way: finding a server, connecting and reconnecting to it,
+//! sending input and keeping the players in sync with what the server says.
+//!
+//! [`NetworkPlugin`] gives every player the server tells us about an entity with a [`NetworkPlayer`], its
+//! [`PlayerColor`], its [`PlayerPosition`] in server coordinates and a [`PlayerName`] once it has one.
+//! Each client only adds what it takes to draw them, after [`NetworkSync`]. Headless clients, like the server's
+//! test harness, use [`SyncPlugin`] on a connection of their own instead.
+
+pub mod connection;
+pub mod sync;
+#[cfg(test)]
+mod tests;
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_renet::netcode::NetcodeClientPlugin;
+use bevy_renet::renet::{ClientId, RenetClient};
+use bevy_renet::{RenetClientPlugin, client_connected, client_just_connected};
+use protocol::interpolation::ServerClock;
+use protocol::movement::Prediction;
+use protocol::netsim::NetworkConditions;
+use protocol::snapshot::SnapshotReceiver;
+use protocol::{Lobby, PaletteMode, PlayerInput, ServerMessages};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Resource, Clone)]
+pub struct ClientSettings {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub server_ip: String,
+    pub server_port: String,
+    /// Token service to request a secure connect token from, connects without authentication when unset
+    pub auth_addr: Option<String>,
+    /// Server directory to pick the least loaded server from, `server_ip` and `server_port` are used when unset
+    pub directory_addr: Option<String>,
+    /// Latency, jitter, loss, reordering and duplication to simulate on the connection, none when unset
+    pub network_conditions: Option<NetworkConditions>,
+    /// Where the session token is kept so a restarted client can reclaim its player
+    pub session_file: PathBuf,
+    /// Palette to ask the server for, so this player can tell the others apart
+    pub palette: Option<PaletteMode>,
+    /// Name to show above this player, sent to the server on connect
+    pub name: Option<String>,
+    /// Linear RGBA color to ask for instead of the assigned one, only sent along with a name
+    pub preferred_color: Option<[f32; 4]>,
+    /// How far behind the server, in seconds, remote players are rendered
+    pub interpolation_delay: f64,
+    /// How long, in seconds, remote players keep moving past their last snapshot when packets are late
+    pub max_extrapolation: f64,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            initial_delay: Duration::from_secs(1),
+            server_ip: env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
+            server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string()),
+            auth_addr: env::var("AUTH_ADDR").ok(),
+            directory_addr: env::var("DIRECTORY_ADDR").ok(),
+            network_conditions: NetworkConditions::from_env(),
+            // One file per client id, so clients started side by side do not take over each other's player
+            session_file: env::var("SESSION_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| env::temp_dir().join(format!("multiplayer-bevy-{}.session", *connection::CLIENT_ID))),
+            palette: env::var("PALETTE_MODE").ok().and_then(|s| s.parse().ok()),
+            name: env::var("PLAYER_NAME").ok(),
+            preferred_color: env::var("PLAYER_COLOR")
+                .ok()
+                .and_then(|hex| Srgba::hex(hex).ok())
+                .map(|color| LinearRgba::from(color).to_f32_array()),
+            interpolation_delay: env::var("INTERPOLATION_DELAY_MS")
+                .ok()
+                .and_then(|ms| ms.parse::<f64>().ok())
+                .unwrap_or(100.0)
+                / 1000.0,
+            max_extrapolation: env::var("MAX_EXTRAPOLATION_MS")
+                .ok()
+                .and_then(|ms| ms.parse::<f64>().ok())
+                .unwrap_or(250.0)
+                / 1000.0,
+        }
+    }
+}
+
+/// Input send rate until the server tells us its tick rate
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+
+/// Where the server puts new players, until their first snapshot arrives
+pub const SPAWN_POSITION: Vec3 = Vec3::new(0.0, 0.5, 0.0);
+
+/// Sequence number of the last input sent to the server
+#[derive(Resource, Default)]
+pub struct InputSequence(u32);
+
+/// Inserted when the server refuses this client, stops the reconnect loop
+#[derive(Resource)]
+pub struct RejectedByServer;
+
+/// Inserted when the server says it is shutting down, holds off reconnecting until it has drained this client
+#[derive(Resource)]
+pub struct ServerShutdown {
+    /// How long to wait after the server disconnects us before reconnecting, `None` to stay disconnected
+    pub reconnect_hint: Option<Duration>,
+    /// When the server disconnected us, in seconds since startup
+    pub disconnected_at: Option<f64>,
+}
+
+/// A player the server told us about
+#[derive(Component)]
+pub struct NetworkPlayer {
+    pub id: ClientId,
+}
+
+/// This client's own player
+#[derive(Component)]
+pub struct LocalPlayer;
+
+/// Linear RGBA color the server gave a player, replaced when the player changes it
+#[derive(Component, Clone, Copy)]
+pub struct PlayerColor(pub [f32; 4]);
+
+/// Where a player is in the server's world: predicted for our own player, interpolated for the others
+#[derive(Component, Default)]
+pub struct PlayerPosition(pub Vec3);
+
+/// Name a player picked, shown above it
+#[derive(Component)]
+pub struct PlayerName(pub String);
+
+/// Every message the server sent, read after [`NetworkSync`] to react to one the players do not show
+#[derive(Event, Debug, Clone)]
+pub struct ServerMessageReceived(pub ServerMessages);
+
+/// Systems that apply what the server sent, clients draw the players after them
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NetworkSync;
+
+/// Connect to the server in [`ClientSettings`], picked through the directory if one is set, and keep the
+/// players in sync with it
+pub struct NetworkPlugin;
+
+impl Plugin for NetworkPlugin {
+    fn build(&self, app: &mut App) {
+        let mut settings = app.world().get_resource::<ClientSettings>().cloned().unwrap_or_default();
+        connection::pick_server(&mut settings);
+        let (client, transport) = connection::new_renet_client(&settings, false);
+        app.insert_resource(settings)
+            .insert_resource(client)
+            .insert_resource(transport)
+            .add_plugins((RenetClientPlugin, NetcodeClientPlugin, SyncPlugin))
+            .add_systems(
+                Update,
+                (
+                    connection::network_error_reconnect_system,
+                    connection::periodic_connection_checker_system,
+                )
+                    .run_if(not(resource_exists::<RejectedByServer>)),
+            );
+    }
+}
+
+/// Keep the players in sync with the server the `RenetClient` and `NetcodeClientTransport` resources connect to,
+/// and send it the player input once per tick. Reconnecting is left to whoever made the connection.
+pub struct SyncPlugin;
+
+impl Plugin for SyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClientSettings>()
+            .init_resource::<Lobby>()
+            .init_resource::<PlayerInput>()
+            .init_resource::<InputSequence>()
+            .init_resource::<Prediction>()
+            .init_resource::<ServerClock>()
+            .init_resource::<SnapshotReceiver>()
+            .insert_resource(Time::<Fixed>::from_hz(DEFAULT_TICK_RATE))
+            .add_event::<ServerMessageReceived>()
+            .add_systems(
+                Update,
+                (
+                    sync::client_sync_players,
+                    (sync::apply_prediction_system, sync::interpolate_remote_players),
+                )
+                    .chain()
+                    .in_set(NetworkSync)
+                    .run_if(client_connected),
+            )
+            .add_systems(Update, sync::send_profile_system.run_if(client_just_connected))
+            .add_systems(FixedUpdate, sync::client_send_input.run_if(client_connected));
+    }
+}
+
+/// Update the player input
+pub fn player_input(keyboard_input: Res<ButtonInput<KeyCode>>, mut player_input: ResMut<PlayerInput>) {
+    player_input.left = keyboard_input.pressed(KeyCode::KeyA) || keyboard_input.pressed(KeyCode::ArrowLeft);
+    player_input.right = keyboard_input.pressed(KeyCode::KeyD) || keyboard_input.pressed(KeyCode::ArrowRight);
+    player_input.up = keyboard_input.pressed(KeyCode::KeyW) || keyboard_input.pressed(KeyCode::ArrowUp);
+    player_input.down = keyboard_input.pressed(KeyCode::KeyS) || keyboard_input.pressed(KeyCode::ArrowDown);
+}
+
+/// Exit system that gracefully disconnects from renet on Escape key press
+pub fn exit_system(keyboard_input: Res<ButtonInput<KeyCode>>, client: Option<ResMut<RenetClient>>, mut exit: EventWriter<AppExit>) {
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        println!("Exit requested. Disconnecting gracefully...");
+        if let Some(mut client) = client {
+            client.disconnect();
+        }
+        exit.send(AppExit::Success);
+    }
+}
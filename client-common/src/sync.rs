@@ -0,0 +1,252 @@
+//! Applying server messages and snapshots to the players, and sending input and the profile back.
+
+use crate::{
+    ClientSettings, DEFAULT_TICK_RATE, InputSequence, LocalPlayer, NetworkPlayer, PlayerColor, PlayerName, PlayerPosition,
+    RejectedByServer, SPAWN_POSITION, ServerMessageReceived, ServerShutdown,
+};
+use bevy::prelude::*;
+use bevy_renet::netcode::NetcodeClientTransport;
+use bevy_renet::renet::{DefaultChannel, RenetClient};
+use protocol::interpolation::{ServerClock, SnapshotBuffer};
+use protocol::movement::{Prediction, SimulationSettings};
+use protocol::snapshot::{DeltaSnapshot, SnapshotAck, SnapshotReceiver, dequantize};
+use protocol::{ClientMessages, InputCommand, Lobby, PlayerInput, ServerMessages};
+
+/// Sync player with the server
+#[allow(clippy::too_many_arguments)]
+pub fn client_sync_players(
+    mut commands: Commands,
+    mut client: ResMut<RenetClient>,
+    transport: Res<NetcodeClientTransport>,
+    mut lobby: ResMut<Lobby>,
+    mut settings: ResMut<ClientSettings>,
+    mut prediction: ResMut<Prediction>,
+    simulation: Option<Res<SimulationSettings>>,
+    time: Res<Time>,
+    mut clock: ResMut<ServerClock>,
+    mut snapshots: ResMut<SnapshotReceiver>,
+    mut positions: Query<(&mut PlayerPosition, Option<&mut SnapshotBuffer>)>,
+    mut received: EventWriter<ServerMessageReceived>,
+) {
+    // Spawned through commands, so snapshots handled below cannot reach them yet
+    let mut spawned = Vec::new();
+    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
+        let server_message = match protocol::decode::<ServerMessages>(&message) {
+            Ok(server_message) => server_message,
+            Err(e) => {
+                warn!("⚠️ Dropped a server message of {} bytes that did not decode: {}", message.len(), e);
+                continue;
+            }
+        };
+        received.send(ServerMessageReceived(server_message.clone()));
+        match server_message {
+            ServerMessages::PlayerConnected { id, .. } => {
+                info!("Player {} connected.", id);
+            }
+            ServerMessages::SpawnPlayer { id, color, name } => {
+                let mut player = commands.spawn((NetworkPlayer { id }, PlayerColor(color), PlayerPosition(SPAWN_POSITION)));
+                if let Some(name) = name {
+                    player.insert(PlayerName(name));
+                }
+                if id == transport.client_id() {
+                    player.insert(LocalPlayer);
+                } else {
+                    player.insert(SnapshotBuffer::default());
+                }
+                // Left over from a previous connection
+                spawned.push((id, player.id()));
+                if let Some(previous_entity) = lobby.players.insert(id, player.id()) {
+                    commands.entity(previous_entity).despawn();
+                }
+            }
+            ServerMessages::DespawnPlayer { id } => {
+                if let Some(player_entity) = lobby.players.remove(&id) {
+                    commands.entity(player_entity).despawn();
+                }
+            }
+            ServerMessages::PlayerDisconnected { id } => {
+                info!("Player {} disconnected.", id);
+                if let Some(player_entity) = lobby.players.remove(&id) {
+                    commands.entity(player_entity).despawn();
+                }
+            }
+            ServerMessages::ConnectionRejected { reason } => {
+                error!("❌ Rejected by server: {}", reason);
+                client.disconnect();
+                commands.spawn((
+                    Text::new(format!("Rejected by server: {}", reason)),
+                    Node {
+                        position_type: PositionType::Absolute,
+                        top: Val::Px(12.0),
+                        left: Val::Px(12.0),
+                        ..default()
+                    },
+                ));
+                commands.insert_resource(RejectedByServer);
+                return;
+            }
+            ServerMessages::Redirect { address } => {
+                info!("🔀 Redirected to {}, which holds our session", address);
+                settings.server_ip = address.ip().to_string();
+                settings.server_port = address.port().to_string();
+                // The reconnect check picks up the new address
+                client.disconnect();
+                return;
+            }
+            ServerMessages::ServerShuttingDown { reconnect_hint } => {
+                match reconnect_hint {
+                    Some(hint) => warn!("⚠️ Server is shutting down, reconnecting {:?} after it lets us go", hint),
+                    None => warn!("⚠️ Server is shutting down and asked us not to reconnect"),
+                }
+                // The reconnect check waits for the drain disconnect and the hint
+                commands.insert_resource(ServerShutdown {
+                    reconnect_hint,
+                    disconnected_at: None,
+                });
+            }
+            ServerMessages::Simulation { settings: simulation } => {
+                info!("Server simulation: {:?}", simulation);
+                commands.insert_resource(Time::<Fixed>::from_hz(simulation.tick_rate));
+                commands.insert_resource(simulation);
+                prediction.reset();
+                clock.reset();
+                snapshots.reset();
+            }
+            ServerMessages::SimulationChanged { settings: simulation } => {
+                // Pending inputs are replayed with the new speed on the next snapshot
+                info!("Server simulation changed: {:?}", simulation);
+                commands.insert_resource(simulation);
+            }
+            ServerMessages::ProfileChanged { id, name, color } => {
+                info!("Player {} is now called {}", id, name);
+                if let Some(&player_entity) = lobby.players.get(&id) {
+                    commands.entity(player_entity).insert((PlayerName(name), PlayerColor(color)));
+                }
+            }
+            ServerMessages::ProfileRejected { reason } => {
+                warn!("⚠️ Server refused our profile: {}", reason);
+            }
+            ServerMessages::SessionAssigned { token } => {
+                if let Err(e) = token.save(&settings.session_file) {
+                    warn!("⚠️ Could not save session to {}: {}", settings.session_file.display(), e);
+                }
+            }
+        }
+    }
+
+    let mut ack = None;
+    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
+        let snapshot = match DeltaSnapshot::from_bytes(&message) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("⚠️ Dropped a snapshot of {} bytes that did not decode: {}", message.len(), e);
+                continue;
+            }
+        };
+        let Some(state) = snapshots.receive(&snapshot) else {
+            continue;
+        };
+        ack = Some(SnapshotAck { tick: snapshot.tick });
+        let server_time = tick_time(simulation.as_deref(), snapshot.tick);
+        clock.observe(server_time, time.elapsed_secs_f64());
+        for (player_id, &position) in state.iter() {
+            let translation = dequantize(position);
+            if *player_id == transport.client_id()
+                && let Some(simulation) = &simulation
+            {
+                prediction.reconcile(translation, snapshot.last_input_sequence, simulation);
+            }
+            if let Some(&player_entity) = lobby.players.get(player_id)
+                && let Ok((mut player_position, buffer)) = positions.get_mut(player_entity)
+            {
+                match buffer {
+                    Some(mut buffer) => buffer.push(server_time, translation),
+                    None => player_position.0 = translation,
+                }
+            }
+        }
+    }
+    // Later snapshots leave out players that do not move, so new players start where the newest state has them
+    if let Some((tick, state)) = snapshots.newest() {
+        for (id, player_entity) in spawned {
+            // Gone again already, e.g. despawned by a later message this frame
+            let Some(&position) = state.get(&id).filter(|_| lobby.players.get(&id) == Some(&player_entity)) else {
+                continue;
+            };
+            let translation = dequantize(position);
+            let mut player = commands.entity(player_entity);
+            player.insert(PlayerPosition(translation));
+            if id != transport.client_id() {
+                let mut buffer = SnapshotBuffer::default();
+                buffer.push(tick_time(simulation.as_deref(), tick), translation);
+                player.insert(buffer);
+            }
+        }
+    }
+    // Acknowledging the newest snapshot is enough, the server only needs one baseline
+    if let Some(ack) = ack {
+        client.send_message(DefaultChannel::Unreliable, bincode::serialize(&ack).unwrap());
+    }
+}
+
+/// Server time of a tick, at the default tick rate until the server sent its simulation settings
+fn tick_time(simulation: Option<&SimulationSettings>, tick: u32) -> f64 {
+    simulation.map_or(tick as f64 / DEFAULT_TICK_RATE, |simulation| simulation.tick_time(tick))
+}
+
+/// Ask the server for the name and color in the settings, once per connection
+pub fn send_profile_system(settings: Res<ClientSettings>, mut client: ResMut<RenetClient>) {
+    if let Some(name) = &settings.name {
+        let message = ClientMessages::SetProfile {
+            name: name.clone(),
+            preferred_color: settings.preferred_color,
+        };
+        client.send_message(DefaultChannel::ReliableOrdered, bincode::serialize(&message).unwrap());
+    }
+}
+
+/// Show the local player at its predicted position instead of the last snapshot
+pub fn apply_prediction_system(prediction: Res<Prediction>, mut query: Query<&mut PlayerPosition, With<LocalPlayer>>) {
+    if let Some(translation) = prediction.translation {
+        for mut position in query.iter_mut() {
+            position.0 = translation;
+        }
+    }
+}
+
+/// Render remote players a little behind the server so they move smoothly between snapshots
+pub fn interpolate_remote_players(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    settings: Res<ClientSettings>,
+    mut query: Query<(&mut PlayerPosition, &mut SnapshotBuffer)>,
+) {
+    let Some(server_time) = clock.server_time(time.elapsed_secs_f64()) else {
+        return;
+    };
+    for (mut position, mut buffer) in query.iter_mut() {
+        if let Some(translation) = buffer.sample(server_time - settings.interpolation_delay, settings.max_extrapolation) {
+            position.0 = translation;
+        }
+    }
+}
+
+/// Send the player input to the server once per tick, and predict its effect right away
+pub fn client_send_input(
+    player_input: Res<PlayerInput>,
+    mut sequence: ResMut<InputSequence>,
+    mut prediction: ResMut<Prediction>,
+    simulation: Option<Res<SimulationSettings>>,
+    mut client: ResMut<RenetClient>,
+) {
+    sequence.0 = sequence.0.wrapping_add(1);
+    let command = InputCommand {
+        sequence: sequence.0,
+        input: player_input.clone(),
+    };
+    let input_message = bincode::serialize(&ClientMessages::Input { command: command.clone() }).unwrap();
+    client.send_message(DefaultChannel::ReliableOrdered, input_message);
+    if let Some(simulation) = simulation {
+        prediction.predict(command, &simulation);
+    }
+}
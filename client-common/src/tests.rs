@@ -0,0 +1,120 @@
+//! Tests of the client systems against an in-memory server, no sockets are read and nothing is drawn.
+
+use super::*;
+use bevy_renet::netcode::{ClientAuthentication, NetcodeClientTransport};
+use bevy_renet::renet::{ConnectionConfig, DefaultChannel, RenetServer};
+use protocol::interpolation::SnapshotBuffer;
+use protocol::snapshot::{SnapshotSender, WorldState, quantize};
+use protocol::{PROTOCOL_ID, ServerMessages};
+use std::net::UdpSocket;
+
+const LOCAL_ID: ClientId = 1;
+const REMOTE_ID: ClientId = 2;
+
+/// A client app running the sync system, connected as [`LOCAL_ID`] to an in-memory server
+fn connected_client() -> (App, RenetServer) {
+    let mut server = RenetServer::new(ConnectionConfig::default());
+    let client = server.new_local_client(LOCAL_ID);
+    // Only there for the client id, the connection goes through the in-memory server
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let authentication = ClientAuthentication::Unsecure {
+        client_id: LOCAL_ID,
+        protocol_id: PROTOCOL_ID,
+        server_addr: socket.local_addr().unwrap(),
+        user_data: None,
+    };
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .insert_resource(ClientSettings::default())
+        .insert_resource(client)
+        .insert_resource(NetcodeClientTransport::new(Duration::ZERO, authentication, socket).unwrap())
+        .init_resource::<Lobby>()
+        .init_resource::<Prediction>()
+        .init_resource::<ServerClock>()
+        .init_resource::<SnapshotReceiver>()
+        .add_event::<ServerMessageReceived>()
+        .add_systems(Update, sync::client_sync_players);
+    (app, server)
+}
+
+/// Deliver what the server sent, then run one frame of the client
+fn deliver(app: &mut App, server: &mut RenetServer) {
+    let mut client = app.world_mut().resource_mut::<RenetClient>();
+    server.process_local_client(LOCAL_ID, &mut client).unwrap();
+    app.update();
+}
+
+fn spawn(server: &mut RenetServer, id: ClientId) {
+    let message = ServerMessages::SpawnPlayer {
+        id,
+        color: [1.0, 0.0, 0.0, 1.0],
+        name: None,
+    };
+    server.send_message(LOCAL_ID, DefaultChannel::ReliableOrdered, bincode::serialize(&message).unwrap());
+}
+
+/// Where the client shows a player, `None` while it has no entity
+fn position(app: &mut App, id: ClientId) -> Option<Vec3> {
+    let entity = *app.world().resource::<Lobby>().players.get(&id)?;
+    app.world().get::<PlayerPosition>(entity).map(|position| position.0)
+}
+
+#[test]
+fn players_spawned_with_a_snapshot_in_the_same_frame_start_where_it_has_them() {
+    let (mut app, mut server) = connected_client();
+    let mut sender = SnapshotSender::default();
+    let remote = Vec3::new(3.0, 0.5, -2.0);
+    let local = Vec3::new(-1.0, 0.5, 4.0);
+    let state = WorldState::from([(REMOTE_ID, quantize(remote)), (LOCAL_ID, quantize(local))]);
+
+    spawn(&mut server, REMOTE_ID);
+    spawn(&mut server, LOCAL_ID);
+    server.send_message(LOCAL_ID, DefaultChannel::Unreliable, sender.encode(1, &state, 0).to_bytes());
+    deliver(&mut app, &mut server);
+
+    assert_eq!(position(&mut app, REMOTE_ID), Some(remote));
+    assert_eq!(position(&mut app, LOCAL_ID), Some(local));
+    let entity = app.world().resource::<Lobby>().players[&REMOTE_ID];
+    assert!(app.world().get::<SnapshotBuffer>(entity).is_some());
+    assert!(
+        app.world()
+            .get::<LocalPlayer>(app.world().resource::<Lobby>().players[&LOCAL_ID])
+            .is_some()
+    );
+}
+
+#[test]
+fn idle_players_spawned_after_their_snapshot_start_where_it_has_them() {
+    let (mut app, mut server) = connected_client();
+    let mut sender = SnapshotSender::default();
+    let remote = Vec3::new(3.0, 0.5, -2.0);
+    let state = WorldState::from([(REMOTE_ID, quantize(remote))]);
+
+    server.send_message(LOCAL_ID, DefaultChannel::Unreliable, sender.encode(1, &state, 0).to_bytes());
+    deliver(&mut app, &mut server);
+    // The player has not moved, so the next snapshot leaves it out
+    sender.ack(1);
+    let snapshot = sender.encode(2, &state, 0);
+    assert!(snapshot.changed.is_empty());
+    spawn(&mut server, REMOTE_ID);
+    server.send_message(LOCAL_ID, DefaultChannel::Unreliable, snapshot.to_bytes());
+    deliver(&mut app, &mut server);
+
+    assert_eq!(position(&mut app, REMOTE_ID), Some(remote));
+}
+
+#[test]
+fn players_despawned_in_the_frame_they_spawn_are_left_alone() {
+    let (mut app, mut server) = connected_client();
+    let state = WorldState::from([(REMOTE_ID, quantize(Vec3::ONE))]);
+
+    spawn(&mut server, REMOTE_ID);
+    let message = ServerMessages::DespawnPlayer { id: REMOTE_ID };
+    server.send_message(LOCAL_ID, DefaultChannel::ReliableOrdered, bincode::serialize(&message).unwrap());
+    let snapshot = SnapshotSender::default().encode(1, &state, 0);
+    server.send_message(LOCAL_ID, DefaultChannel::Unreliable, snapshot.to_bytes());
+    deliver(&mut app, &mut server);
+
+    assert_eq!(position(&mut app, REMOTE_ID), None);
+    assert_eq!(app.world_mut().query::<&NetworkPlayer>().iter(app.world()).count(), 0);
+}
@@ -1,92 +1,34 @@
-use once_cell::sync::Lazy;
 use std::env;
-use std::time::SystemTime;
 
-use bevy::{app::AppExit, prelude::*, render::mesh::PlaneMeshBuilder};
-use bevy_renet::netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport, NetcodeTransportError};
-use bevy_renet::renet::{ClientId, ConnectionConfig, DefaultChannel, RenetClient};
-use bevy_renet::{RenetClientPlugin, client_connected};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, net::UdpSocket};
-use std::{thread, time::Duration};
+use bevy::{prelude::*, render::mesh::PlaneMeshBuilder};
+use bevy_renet::renet::ClientId;
+use client_common::{
+    ClientSettings, NetworkPlayer, NetworkPlugin, NetworkSync, PlayerColor, PlayerName, PlayerPosition, exit_system, player_input,
+};
+use protocol::{Lobby, PlayerInput};
 
-const PROTOCOL_ID: u64 = 7;
-
-pub static CLIENT_ID: Lazy<u64> = Lazy::new(|| {
-    env::var("CLIENT_ID")
-        .ok()
-        .and_then(|id_str| id_str.parse().ok())
-        .unwrap_or_else(|| {
-            let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-            current_time.as_millis() as u64
-        })
-});
-
-// Updated ClientSettings with additional fields.
-#[derive(Resource, Clone)]
-struct ClientSettings {
-    max_retries: u32,
-    initial_delay: Duration,
-    server_ip: String,
-    server_port: String,
+/// Text following a player around the screen
+#[derive(Component)]
+struct NameLabel {
+    player: Entity,
 }
 
-impl Default for ClientSettings {
-    fn default() -> Self {
-        Self {
-            max_retries: 10,
-            initial_delay: Duration::from_secs(1),
-            server_ip: env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string()),
-        }
-    }
-}
-
-#[derive(Debug, Default, Serialize, Deserialize, Component, Resource, Clone)]
-struct PlayerInput {
-    up: bool,
-    down: bool,
-    left: bool,
-    right: bool,
-}
-
-#[derive(Debug, Default, Resource)]
-struct Lobby {
-    players: HashMap<ClientId, Entity>,
-}
-
-#[derive(Debug, Serialize, Deserialize, Component)]
-enum ServerMessages {
-    PlayerConnected { id: ClientId, color: [f32; 4] },
-    PlayerDisconnected { id: ClientId },
-}
-
-#[derive(Resource, Default)]
-struct InitialSyncDone(bool);
-
 /// Run bevy client
 fn main() {
-    let client_settings = ClientSettings::default();
     let multiplayer = env::var("MULTIPLAYER").unwrap_or_default().to_lowercase() == "true";
 
     let mut app = App::new();
     app.add_plugins(DefaultPlugins)
         .init_resource::<Lobby>()
         .init_resource::<PlayerInput>()
-        .init_resource::<InitialSyncDone>()
-        .insert_resource(client_settings.clone());
+        .insert_resource(ClientSettings::default());
 
     if multiplayer {
-        let (renet_client, renet_transport) = new_renet_client(&client_settings);
-        app.insert_resource(renet_client)
-            .insert_resource(renet_transport)
-            .add_plugins((RenetClientPlugin, NetcodeClientPlugin))
+        app.add_plugins(NetworkPlugin)
             .add_systems(Startup, setup)
-            .add_systems(
-                Update,
-                (player_input, client_send_input, client_sync_players).run_if(client_connected),
-            )
-            .add_systems(Update, (reconnect_on_error_system, reconnect_check_system, exit_system));
+            .add_systems(Update, (player_input, exit_system))
+            .add_systems(Update, (draw_players_system, place_players_system).chain().after(NetworkSync))
+            .add_systems(PostUpdate, name_label_system.after(TransformSystem::TransformPropagate));
     } else {
         // Local mode: spawn local player, update input, then move the player.
         app.add_systems(Startup, (setup, local_spawn_player)).add_systems(
@@ -98,113 +40,70 @@ fn main() {
     app.run();
 }
 
-/// Create a new RenetClient and NetcodeClientTransport using settings from ClientSettings
-fn new_renet_client(settings: &ClientSettings) -> (RenetClient, NetcodeClientTransport) {
-    let server_addr = format!("{}:{}", settings.server_ip, settings.server_port).parse().unwrap();
-
-    info!("Connecting to server at: {}", server_addr);
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    let client_id = *CLIENT_ID;
-    info!("Using CLIENT_ID={}", client_id);
-
-    let authentication = ClientAuthentication::Unsecure {
-        client_id,
-        protocol_id: PROTOCOL_ID,
-        server_addr,
-        user_data: None,
-    };
-
-    let mut retries = 0;
-    let mut delay = settings.initial_delay;
-
-    while retries < settings.max_retries {
-        match NetcodeClientTransport::new(current_time, authentication.clone(), socket.try_clone().unwrap()) {
-            Ok(transport) => {
-                println!("✅ Connected to server on attempt {}", retries + 1);
-                let client = RenetClient::new(ConnectionConfig::default());
-                return (client, transport);
-            }
-            Err(e) => {
-                println!(
-                    "⚠️ Connection attempt {} failed: {}. Retrying in {}s...",
-                    retries + 1,
-                    e,
-                    delay.as_secs()
-                );
-                thread::sleep(delay);
-                delay *= 2; // Exponential backoff (1s, 2s, 4s, 8s...)
-                retries += 1;
-            }
-        }
+/// Give every player the server told us about a cube, in the color the server assigned it
+fn draw_players_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    added: Query<(Entity, &PlayerPosition), Added<NetworkPlayer>>,
+    recolored: Query<(Entity, &PlayerColor), Changed<PlayerColor>>,
+) {
+    for (player_entity, position) in added.iter() {
+        commands.entity(player_entity).insert((
+            Mesh3d(meshes.add(Cuboid::from_size(Vec3::splat(1.0)))),
+            Transform::from_translation(position.0),
+        ));
+    }
+    for (player_entity, &PlayerColor(color)) in recolored.iter() {
+        commands.entity(player_entity).insert(MeshMaterial3d(
+            materials.add(Color::linear_rgba(color[0], color[1], color[2], color[3])),
+        ));
     }
+}
 
-    panic!("❌ Failed to connect to server after {} attempts.", settings.max_retries);
+/// Move every cube to where its player is
+fn place_players_system(mut query: Query<(&PlayerPosition, &mut Transform), Changed<PlayerPosition>>) {
+    for (position, mut transform) in query.iter_mut() {
+        transform.translation = position.0;
+    }
 }
 
-/// Sync player with the server
-fn client_sync_players(
+/// Keep a label with its name above every named player, and remove labels of players that are gone
+fn name_label_system(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut client: ResMut<RenetClient>,
-    mut lobby: ResMut<Lobby>,
-    mut initial_sync: ResMut<InitialSyncDone>, // added parameter
+    camera: Query<(&Camera, &GlobalTransform)>,
+    named: Query<(Entity, &PlayerName), Added<PlayerName>>,
+    players: Query<(Ref<PlayerName>, &GlobalTransform)>,
+    mut labels: Query<(Entity, &NameLabel, &mut Text, &mut Node, &ComputedNode)>,
 ) {
-    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
-        let server_message: ServerMessages = bincode::deserialize(&message).unwrap();
-        match server_message {
-            ServerMessages::PlayerConnected { id, color } => {
-                info!("Player {} connected.", id);
-                let player_entity = commands
-                    .spawn((
-                        Mesh3d(meshes.add(Cuboid::from_size(Vec3::splat(1.0)))),
-                        // Use the color provided by the server.
-                        MeshMaterial3d(materials.add(Color::srgba(color[0], color[1], color[2], color[3]))),
-                        Transform::from_xyz(0.0, 0.5, 0.0),
-                    ))
-                    .id();
-                lobby.players.insert(id, player_entity);
-            }
-            ServerMessages::PlayerDisconnected { id } => {
-                info!("Player {} disconnected.", id);
-                if let Some(player_entity) = lobby.players.remove(&id) {
-                    commands.entity(player_entity).despawn();
-                }
-            }
-        }
+    for (player, name) in named.iter() {
+        commands.spawn((
+            Text::new(name.0.clone()),
+            Node {
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            NameLabel { player },
+        ));
     }
-
-    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-        // Now each value is a tuple of (position, color).
-        let players: HashMap<ClientId, ([f32; 3], [f32; 4])> = bincode::deserialize(&message).unwrap();
-        for (player_id, (translation, color)) in players.iter() {
-            if let Some(&player_entity) = lobby.players.get(player_id) {
-                commands.entity(player_entity).insert(Transform {
-                    translation: (*translation).into(),
-                    ..Default::default()
-                });
-                // Update color so that it stays in sync.
-                commands
-                    .entity(player_entity)
-                    .insert(MeshMaterial3d(materials.add(Color::srgba(color[0], color[1], color[2], color[3]))));
-            } else if !initial_sync.0 {
-                // Spawn missing players during initial sync using the provided color.
-                let player_entity = commands
-                    .spawn((
-                        Mesh3d(meshes.add(Cuboid::from_size(Vec3::splat(1.0)))),
-                        MeshMaterial3d(materials.add(Color::srgba(color[0], color[1], color[2], color[3]))),
-                        Transform {
-                            translation: (*translation).into(),
-                            ..Default::default()
-                        },
-                    ))
-                    .id();
-                lobby.players.insert(*player_id, player_entity);
-            }
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    for (label, name_label, mut text, mut node, computed) in labels.iter_mut() {
+        let Ok((name, transform)) = players.get(name_label.player) else {
+            commands.entity(label).despawn();
+            continue;
+        };
+        if name.is_changed() {
+            text.0 = name.0.clone();
         }
-        // Mark initial sync complete so that new missing entries are ignored.
-        initial_sync.0 = true;
+        // Centered just above the top of the cube
+        let Ok(position) = camera.world_to_viewport(camera_transform, transform.translation() + Vec3::Y * 0.7) else {
+            continue;
+        };
+        let size = computed.size() * computed.inverse_scale_factor();
+        node.left = Val::Px(position.x - size.x / 2.0);
+        node.top = Val::Px(position.y - size.y);
     }
 }
 
@@ -230,105 +129,6 @@ fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials
     ));
 }
 
-/// Update the player input
-fn player_input(keyboard_input: Res<ButtonInput<KeyCode>>, mut player_input: ResMut<PlayerInput>) {
-    player_input.left = keyboard_input.pressed(KeyCode::KeyA) || keyboard_input.pressed(KeyCode::ArrowLeft);
-    player_input.right = keyboard_input.pressed(KeyCode::KeyD) || keyboard_input.pressed(KeyCode::ArrowRight);
-    player_input.up = keyboard_input.pressed(KeyCode::KeyW) || keyboard_input.pressed(KeyCode::ArrowUp);
-    player_input.down = keyboard_input.pressed(KeyCode::KeyS) || keyboard_input.pressed(KeyCode::ArrowDown);
-}
-
-/// Exit system that gracefully disconnects from renet on Escape key press
-fn exit_system(keyboard_input: Res<ButtonInput<KeyCode>>, client: Option<ResMut<RenetClient>>, mut exit: EventWriter<AppExit>) {
-    if keyboard_input.just_pressed(KeyCode::Escape) {
-        println!("Exit requested. Disconnecting gracefully...");
-        if let Some(mut client) = client {
-            client.disconnect();
-        }
-        exit.send(AppExit::Success);
-    }
-}
-
-/// Send the player input to the server
-fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>) {
-    let input_message = bincode::serialize(&*player_input).unwrap();
-    client.send_message(DefaultChannel::ReliableOrdered, input_message);
-}
-
-/// Reconnect to the server if an error occurs
-#[allow(clippy::never_loop)]
-fn reconnect_on_error_system(
-    mut commands: Commands,
-    mut renet_error: EventReader<NetcodeTransportError>,
-    time: Res<Time>,
-    mut last_check: Local<f64>,
-) {
-    // Only check once per second
-    if time.elapsed_secs_f64() - *last_check < 1.0 {
-        return;
-    }
-    *last_check = time.elapsed_secs_f64();
-
-    for e in renet_error.read() {
-        error!("⚠️ Connection lost: {:?}", e);
-
-        // Attempt to reconnect
-        println!("🔄 Attempting to reconnect...");
-
-        // Remove the old client resources
-        commands.remove_resource::<RenetClient>();
-        commands.remove_resource::<NetcodeClientTransport>();
-
-        // Create a new client and transport
-        let (new_client, new_transport) = new_renet_client(&ClientSettings {
-            max_retries: 10,
-            initial_delay: Duration::from_secs(1),
-            server_ip: env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string()),
-        });
-
-        // Re-insert the new client resources
-        commands.insert_resource(new_client);
-        commands.insert_resource(new_transport);
-
-        println!("✅ Reconnection attempt completed.");
-    }
-}
-
-/// Check the system to see if the client is connected
-fn reconnect_check_system(mut commands: Commands, client: Res<RenetClient>, time: Res<Time>, mut last_check: Local<f64>) {
-    // Only check once per second
-    if time.elapsed_secs_f64() - *last_check < 1.0 {
-        return;
-    }
-    *last_check = time.elapsed_secs_f64();
-
-    // 🔹 Prevent reconnecting if already connected
-    if client.is_connected() {
-        return;
-    }
-
-    println!("⚠️ Connection lost. Attempting to reconnect...");
-
-    // Remove old client
-    commands.remove_resource::<RenetClient>();
-    commands.remove_resource::<NetcodeClientTransport>();
-
-    // Create a new client and transport
-    let (new_client, new_transport) = new_renet_client(&ClientSettings {
-        max_retries: 10,
-        initial_delay: Duration::from_secs(1),
-        server_ip: env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
-        server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "5000".to_string()),
-    });
-
-    // Reinsert the new client
-    commands.insert_resource(new_client);
-    commands.insert_resource(new_transport);
-
-    println!("✅ Reconnected to server!");
-}
-
 /// For local simulation, add a simple system that updates transformations using local input
 fn local_move_players_system(mut query: Query<(&mut Transform, &PlayerInput)>, time: Res<Time>) {
     for (mut transform, input) in query.iter_mut() {
@@ -364,9 +164,9 @@ fn local_spawn_player(
 
 /// Update local player's PlayerInput component from the global resource
 fn local_update_player_input(player_input_res: Res<PlayerInput>, lobby: Res<Lobby>, mut query: Query<&mut PlayerInput>) {
-    if let Some(&local_entity) = lobby.players.get(&0) {
-        if let Ok(mut component) = query.get_mut(local_entity) {
-            *component = player_input_res.clone();
-        }
+    if let Some(&local_entity) = lobby.players.get(&0)
+        && let Ok(mut component) = query.get_mut(local_entity)
+    {
+        *component = player_input_res.clone();
     }
 }
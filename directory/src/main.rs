@@ -0,0 +1,113 @@
+#[cfg(test)]
+mod tests;
+
+use protocol::ProtocolVersion;
+use protocol::directory::{DirectoryRequest, DirectoryResponse, ServerStatus, read_message, write_message};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+struct DirectorySettings {
+    port: u16,
+    /// Servers that missed heartbeats for this long are no longer handed out
+    server_timeout: Duration,
+}
+
+impl Default for DirectorySettings {
+    fn default() -> Self {
+        Self {
+            port: env::var("DIRECTORY_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(5002),
+            server_timeout: Duration::from_secs_f64(env::var("SERVER_TIMEOUT_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(6.0)),
+        }
+    }
+}
+
+/// Latest heartbeat of every server, by the address clients reach it at
+#[derive(Debug, Default)]
+struct Directory {
+    servers: HashMap<SocketAddr, (ServerStatus, Instant)>,
+}
+
+impl Directory {
+    fn heartbeat(&mut self, status: ServerStatus, now: Instant) {
+        if !self.servers.contains_key(&status.address) {
+            println!("Registered server {} with protocol {}", status.address, status.version);
+        }
+        self.servers.insert(status.address, (status, now));
+    }
+
+    /// Forget servers that stopped sending heartbeats
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.servers.retain(|address, (_, last_seen)| {
+            let alive = now.duration_since(*last_seen) < timeout;
+            if !alive {
+                println!("Server {} timed out", address);
+            }
+            alive
+        });
+    }
+
+    /// The least loaded live server a client speaking `version` can join
+    fn find(&self, version: &ProtocolVersion) -> Option<ServerStatus> {
+        self.servers
+            .values()
+            .map(|(status, _)| status)
+            .filter(|status| status.can_take(version))
+            .min_by(|a, b| a.load().total_cmp(&b.load()).then(a.players.cmp(&b.players)))
+            .cloned()
+    }
+}
+
+/// Run the server directory
+fn main() {
+    let settings = DirectorySettings::default();
+    println!("{:?}", settings);
+    let listener = TcpListener::bind(("0.0.0.0", settings.port)).unwrap();
+    println!("Directory listening on port: {}", settings.port);
+    serve(listener, Arc::new(Mutex::new(Directory::default())), settings.server_timeout);
+}
+
+/// Answer requests until the listener fails
+fn serve(listener: TcpListener, directory: Arc<Mutex<Directory>>, server_timeout: Duration) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let directory = directory.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle(stream, &directory, server_timeout) {
+                eprintln!("Failed to answer {:?}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Read one request and write its response
+fn handle(mut stream: TcpStream, directory: &Mutex<Directory>, server_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request: DirectoryRequest = read_message(&mut stream)?;
+    let now = Instant::now();
+    let response = {
+        let mut directory = directory.lock().unwrap();
+        directory.expire(now, server_timeout);
+        match request {
+            DirectoryRequest::Heartbeat(status) => {
+                directory.heartbeat(status, now);
+                DirectoryResponse::Registered
+            }
+            DirectoryRequest::FindServer { version } => DirectoryResponse::Server(directory.find(&version)),
+        }
+    };
+    write_message(&mut stream, &response)?;
+    Ok(())
+}
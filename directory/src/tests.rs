@@ -0,0 +1,70 @@
+//! Tests of the directory, over TCP with the same helpers servers and clients use.
+
+use super::*;
+use protocol::directory::{find_server, send_heartbeat};
+
+/// Directory on a free port, returns its address
+fn start_directory(server_timeout: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || serve(listener, Arc::new(Mutex::new(Directory::default())), server_timeout));
+    addr
+}
+
+fn status(port: u16, players: u32, capacity: u32) -> ServerStatus {
+    ServerStatus {
+        address: SocketAddr::from(([127, 0, 0, 1], port)),
+        players,
+        capacity,
+        version: ProtocolVersion::current(),
+        accepting: true,
+    }
+}
+
+#[test]
+fn finds_least_loaded_server() {
+    let directory = start_directory(Duration::from_secs(60));
+    assert_eq!(find_server(directory, ProtocolVersion::current()).unwrap(), None);
+
+    send_heartbeat(directory, &status(5000, 8, 10)).unwrap();
+    send_heartbeat(directory, &status(5001, 3, 10)).unwrap();
+    send_heartbeat(directory, &status(5002, 5, 64)).unwrap();
+    let found = find_server(directory, ProtocolVersion::current()).unwrap().unwrap();
+    assert_eq!(found.address.port(), 5002);
+
+    // A heartbeat replaces the previous entry of the same server
+    send_heartbeat(directory, &status(5002, 60, 64)).unwrap();
+    let found = find_server(directory, ProtocolVersion::current()).unwrap().unwrap();
+    assert_eq!(found.address.port(), 5001);
+}
+
+#[test]
+fn skips_full_draining_and_incompatible_servers() {
+    let directory = start_directory(Duration::from_secs(60));
+    send_heartbeat(directory, &status(5000, 10, 10)).unwrap();
+    send_heartbeat(
+        directory,
+        &ServerStatus {
+            accepting: false,
+            ..status(5001, 0, 10)
+        },
+    )
+    .unwrap();
+    let mut old = status(5002, 0, 10);
+    old.version.minor += 1;
+    send_heartbeat(directory, &old).unwrap();
+    assert_eq!(find_server(directory, ProtocolVersion::current()).unwrap(), None);
+
+    // Clients on that version still find the server that speaks it
+    let found = find_server(directory, old.version).unwrap().unwrap();
+    assert_eq!(found.address.port(), 5002);
+}
+
+#[test]
+fn forgets_silent_servers() {
+    let directory = start_directory(Duration::from_millis(200));
+    send_heartbeat(directory, &status(5000, 0, 10)).unwrap();
+    assert!(find_server(directory, ProtocolVersion::current()).unwrap().is_some());
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(find_server(directory, ProtocolVersion::current()).unwrap(), None);
+}
@@ -0,0 +1,21 @@
+use std::process::Command;
+
+/// Embed the git revision the crate was built from as `BUILD_HASH`
+fn main() {
+    println!("cargo:rerun-if-env-changed=BUILD_HASH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    let hash = std::env::var("BUILD_HASH")
+        .ok()
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUILD_HASH={}", hash);
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?;
+    Some(hash.trim().to_string())
+}
@@ -0,0 +1,84 @@
+//! Connect token exchange between clients and the token service.
+//!
+//! A client opens a TCP connection to the token service and sends a length prefixed [`TokenRequest`].
+//! The service answers with a netcode [`ConnectToken`] signed with the [`PrivateKey`] it shares with the
+//! game servers, then closes the connection.
+
+use crate::{Handshake, decode, hex};
+use bevy_renet::netcode::{ConnectToken, NETCODE_KEY_BYTES};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Environment variable holding the hex encoded key shared by the servers and the token service
+pub const PRIVATE_KEY_ENV: &str = "NETCODE_PRIVATE_KEY";
+
+/// How long a client waits on the token service before giving up
+const TOKEN_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Key used to sign and verify netcode connect tokens
+#[derive(Clone, PartialEq, Eq)]
+pub struct PrivateKey(pub [u8; NETCODE_KEY_BYTES]);
+
+impl PrivateKey {
+    /// Parse a key from 64 hex characters
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        hex::decode(hex).map(Self)
+    }
+
+    /// Encode the key as lowercase hex
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    /// Read the key from [`PRIVATE_KEY_ENV`], `None` if it is not set
+    pub fn from_env() -> Option<Self> {
+        let hex = env::var(PRIVATE_KEY_ENV).ok()?;
+        Some(Self::from_hex(&hex).unwrap_or_else(|| panic!("{} must be {} hex characters", PRIVATE_KEY_ENV, NETCODE_KEY_BYTES * 2)))
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrivateKey(<redacted>)")
+    }
+}
+
+/// Sent by a client to the token service, the handshake ends up in the token's user data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub handshake: Handshake,
+    /// Server the client wants to join, listed first in the token if the service knows it
+    pub preferred_server: Option<SocketAddr>,
+}
+
+/// Write a length prefixed [`TokenRequest`]
+pub fn write_token_request(writer: &mut impl Write, request: &TokenRequest) -> io::Result<()> {
+    let bytes = bincode::serialize(request).map_err(io::Error::other)?;
+    writer.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    writer.write_all(&bytes)
+}
+
+/// Read a length prefixed [`TokenRequest`]
+pub fn read_token_request(reader: &mut impl Read) -> io::Result<TokenRequest> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    decode(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Ask the token service at `auth_addr` for a connect token
+pub fn request_connect_token(auth_addr: impl ToSocketAddrs, request: &TokenRequest) -> io::Result<ConnectToken> {
+    let addr = auth_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "token service address did not resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TOKEN_REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(TOKEN_REQUEST_TIMEOUT))?;
+    write_token_request(&mut stream, request)?;
+    ConnectToken::read(&mut stream).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
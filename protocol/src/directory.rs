@@ -0,0 +1,106 @@
+//! Server directory, where game servers announce themselves and clients find one to join.
+//!
+//! Servers send a [`DirectoryRequest::Heartbeat`] every [`HEARTBEAT_INTERVAL`]. Clients send a
+//! [`DirectoryRequest::FindServer`] and connect to the least loaded compatible server in the reply.
+//! Every exchange is one length prefixed request and one length prefixed [`DirectoryResponse`] over a fresh TCP connection.
+
+use crate::{ProtocolVersion, decode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How often servers announce themselves
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a server or client waits on the directory before giving up
+const DIRECTORY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a server reports about itself in each heartbeat
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// Address clients connect to
+    pub address: SocketAddr,
+    pub players: u32,
+    pub capacity: u32,
+    pub version: ProtocolVersion,
+    /// False while the server is draining or otherwise not ready for new players
+    pub accepting: bool,
+}
+
+impl ServerStatus {
+    /// Fraction of the server's capacity in use
+    pub fn load(&self) -> f64 {
+        self.players as f64 / self.capacity.max(1) as f64
+    }
+
+    /// Whether a client speaking `version` can be sent here
+    pub fn can_take(&self, version: &ProtocolVersion) -> bool {
+        self.accepting && self.players < self.capacity && self.version.is_compatible_with(version)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectoryRequest {
+    /// Sent by a server, registers it or refreshes its entry
+    Heartbeat(ServerStatus),
+    /// Sent by a client looking for a server that speaks its protocol version
+    FindServer { version: ProtocolVersion },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectoryResponse {
+    Registered,
+    /// The least loaded server that can take the client, `None` if every server is full or incompatible
+    Server(Option<ServerStatus>),
+}
+
+/// Write a length prefixed directory message
+pub fn write_message(writer: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    let bytes = bincode::serialize(message).map_err(io::Error::other)?;
+    writer.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    writer.write_all(&bytes)
+}
+
+/// Read a length prefixed directory message
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<T> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    decode(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Send one request to the directory at `directory_addr` and wait for its response
+fn request(directory_addr: impl ToSocketAddrs, request: &DirectoryRequest) -> io::Result<DirectoryResponse> {
+    let addr = directory_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "directory address did not resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, DIRECTORY_TIMEOUT)?;
+    stream.set_read_timeout(Some(DIRECTORY_TIMEOUT))?;
+    stream.set_write_timeout(Some(DIRECTORY_TIMEOUT))?;
+    write_message(&mut stream, request)?;
+    read_message(&mut stream)
+}
+
+fn unexpected(response: DirectoryResponse) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected directory response {:?}", response))
+}
+
+/// Register a server with the directory, or refresh its entry
+pub fn send_heartbeat(directory_addr: impl ToSocketAddrs, status: &ServerStatus) -> io::Result<()> {
+    match request(directory_addr, &DirectoryRequest::Heartbeat(status.clone()))? {
+        DirectoryResponse::Registered => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+/// Ask the directory for the least loaded server a client speaking `version` can join
+pub fn find_server(directory_addr: impl ToSocketAddrs, version: ProtocolVersion) -> io::Result<Option<ServerStatus>> {
+    match request(directory_addr, &DirectoryRequest::FindServer { version })? {
+        DirectoryResponse::Server(server) => Ok(server),
+        response => Err(unexpected(response)),
+    }
+}
@@ -0,0 +1,82 @@
+//! Snapshot interpolation for players the client does not control.
+//!
+//! Snapshots are stamped with the server time of their tick and kept in a [`SnapshotBuffer`] per player. The
+//! client renders remote players a fixed delay behind its estimate of the server time, so there are usually two
+//! snapshots to blend between. When packets are late the last known velocity is extrapolated, up to a limit.
+
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+/// Most snapshots kept per player, about half a second at 60Hz
+const MAX_BUFFERED_SNAPSHOTS: usize = 32;
+
+/// How quickly the server clock estimate follows new samples, between 0 and 1
+const CLOCK_SMOOTHING: f64 = 0.1;
+
+/// Estimate of the server time, learned from the ticks of incoming snapshots
+#[derive(Debug, Default, Resource)]
+pub struct ServerClock {
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    /// Record that a snapshot from `server_time` arrived at `local_time`
+    pub fn observe(&mut self, server_time: f64, local_time: f64) {
+        let sample = server_time - local_time;
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) * CLOCK_SMOOTHING,
+            None => sample,
+        });
+    }
+
+    /// Estimated server time at `local_time`, `None` before the first snapshot
+    pub fn server_time(&self, local_time: f64) -> Option<f64> {
+        self.offset.map(|offset| local_time + offset)
+    }
+
+    /// Forget the estimate, used when connecting to a new server
+    pub fn reset(&mut self) {
+        self.offset = None;
+    }
+}
+
+/// Recent server positions of one player, oldest first
+#[derive(Debug, Default, Component)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(f64, Vec3)>,
+}
+
+impl SnapshotBuffer {
+    /// Add a snapshot, snapshots older than the newest one arrived out of order and are ignored
+    pub fn push(&mut self, server_time: f64, translation: Vec3) {
+        if self.snapshots.back().is_some_and(|&(newest, _)| server_time <= newest) {
+            return;
+        }
+        if self.snapshots.len() >= MAX_BUFFERED_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((server_time, translation));
+    }
+
+    /// Position at `render_time`, extrapolating at most `max_extrapolation` seconds past the newest snapshot
+    pub fn sample(&mut self, render_time: f64, max_extrapolation: f64) -> Option<Vec3> {
+        // Keep one snapshot at or before the render time to blend from
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let &(first_time, first) = self.snapshots.front()?;
+        if render_time <= first_time || self.snapshots.len() == 1 {
+            return Some(first);
+        }
+
+        let (from_time, from) = self.snapshots[0];
+        let (to_time, to) = self.snapshots[1];
+        let t = if render_time <= to_time {
+            (render_time - from_time) / (to_time - from_time)
+        } else {
+            (render_time.min(to_time + max_extrapolation) - from_time) / (to_time - from_time)
+        };
+        Some(from.lerp(to, t as f32))
+    }
+}
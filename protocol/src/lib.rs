@@ -0,0 +1,321 @@
+//! Messages, constants and gameplay rules shared by the server and both clients.
+//!
+//! Every type that crosses the wire lives here so the server and clients always agree on its
+//! bincode layout. Changing the shape of any of these types is a protocol change: bump the
+//! crate version so [`PROTOCOL_VERSION`] changes with it.
+
+pub mod auth;
+pub mod directory;
+mod hex;
+pub mod interpolation;
+pub mod movement;
+pub mod netsim;
+pub mod session;
+pub mod snapshot;
+
+use bevy::prelude::*;
+use bevy_renet::netcode::NETCODE_USER_DATA_BYTES;
+use bevy_renet::renet::ClientId;
+use bincode::Options;
+use movement::SimulationSettings;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use session::SessionToken;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Netcode protocol id, packets with a different id are dropped by the transport.
+///
+/// This must stay the same across releases so that mismatched clients still reach the server and can be
+/// told why they were rejected; compatibility is decided by the [`Handshake`] instead.
+pub const PROTOCOL_ID: u64 = 7;
+
+/// Version of the message types in this crate
+pub const PROTOCOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Largest payload, in bytes, that [`decode`] will read
+pub const MAX_MESSAGE_SIZE: u64 = 1024;
+
+/// Git revision the protocol crate was built from, or `BUILD_HASH` if set at build time
+pub const BUILD_HASH: &str = env!("BUILD_HASH");
+
+/// Decode a message with the same layout as `bincode::serialize`, without trusting its contents.
+///
+/// Payloads larger than [`MAX_MESSAGE_SIZE`], with trailing bytes, or that would allocate past the limit
+/// return an error instead of panicking or exhausting memory.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_SIZE)
+        .deserialize(bytes)
+}
+
+/// Semantic version of the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl ProtocolVersion {
+    /// The version this build speaks, parsed from [`PROTOCOL_VERSION`]
+    pub fn current() -> Self {
+        let mut parts = PROTOCOL_VERSION.split('.').map(|part| part.parse().unwrap_or(0));
+        Self {
+            major: parts.next().unwrap_or(0),
+            minor: parts.next().unwrap_or(0),
+            patch: parts.next().unwrap_or(0),
+        }
+    }
+
+    /// Versions are compatible when the major version matches, or the minor version too while still on 0.x
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        if self.major == 0 {
+            self.major == other.major && self.minor == other.minor
+        } else {
+            self.major == other.major
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Sent by a client in the netcode `user_data` when connecting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub version: ProtocolVersion,
+    pub build_hash: String,
+    /// Token from a previous `ServerMessages::SessionAssigned`, to reclaim that player
+    pub session: Option<SessionToken>,
+    /// Colors the player needs to tell the other players apart
+    pub palette: Option<PaletteMode>,
+    /// Take the session's player over even while another connection still plays it, sent by a client
+    /// replacing a connection it lost. Without it the server refuses the client instead.
+    pub take_over: bool,
+}
+
+impl Handshake {
+    /// Handshake describing this build
+    pub fn current() -> Self {
+        Self {
+            version: ProtocolVersion::current(),
+            build_hash: BUILD_HASH.to_string(),
+            session: None,
+            palette: None,
+            take_over: false,
+        }
+    }
+
+    /// Encode into the fixed size netcode `user_data` buffer
+    pub fn to_user_data(&self) -> [u8; NETCODE_USER_DATA_BYTES] {
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        let encoded = bincode::serialize(self).unwrap();
+        let len = encoded.len().min(NETCODE_USER_DATA_BYTES);
+        user_data[..len].copy_from_slice(&encoded[..len]);
+        user_data
+    }
+
+    /// Decode from netcode `user_data`, returns `None` for clients that did not send a handshake
+    pub fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Self> {
+        if user_data.iter().all(|&b| b == 0) {
+            return None;
+        }
+        bincode::deserialize(user_data).ok()
+    }
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.version, self.build_hash)
+    }
+}
+
+/// Set of colors players are picked from, and the color vision they are told apart with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaletteMode {
+    #[default]
+    Default,
+    /// Safe for players who cannot tell red from green because green cones are missing
+    Deuteranopia,
+    /// Safe for players who cannot tell red from green because red cones are missing
+    Protanopia,
+    /// Few, saturated colors far apart from each other
+    HighContrast,
+}
+
+impl FromStr for PaletteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(Self::Default),
+            "deuteranopia" => Ok(Self::Deuteranopia),
+            "protanopia" => Ok(Self::Protanopia),
+            "high-contrast" => Ok(Self::HighContrast),
+            _ => Err(format!(
+                "unknown palette mode {}, expected default, deuteranopia, protanopia or high-contrast",
+                s
+            )),
+        }
+    }
+}
+
+/// Why the server refused a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    MissingHandshake,
+    IncompatibleVersion {
+        server: Handshake,
+        client: Handshake,
+    },
+    /// Another player still owns this client id and the client did not prove it is the same player, or another
+    /// connection still plays the session the client presented and it did not ask to take it over
+    ClientIdInUse,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::MissingHandshake => write!(f, "client did not send a protocol handshake"),
+            RejectReason::IncompatibleVersion { server, client } => {
+                write!(f, "client protocol {} is incompatible with server protocol {}", client, server)
+            }
+            RejectReason::ClientIdInUse => write!(f, "client id is already in use by another player"),
+        }
+    }
+}
+
+/// Buttons held by a player during one tick
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Component, Resource)]
+pub struct PlayerInput {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+/// One tick of input sent by a client to the server in [`ClientMessages::Input`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct InputCommand {
+    /// Increases by one for every tick the client sends input for
+    pub sequence: u32,
+    pub input: PlayerInput,
+}
+
+/// Longest player name, in characters
+pub const MAX_NAME_CHARS: usize = 16;
+
+/// Messages sent by a client on `DefaultChannel::ReliableOrdered`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessages {
+    Input {
+        command: InputCommand,
+    },
+    /// Ask for a name, and a linear RGBA color instead of the assigned one, answered by
+    /// [`ServerMessages::ProfileChanged`] or [`ServerMessages::ProfileRejected`]
+    SetProfile {
+        name: String,
+        preferred_color: Option<[f32; 4]>,
+    },
+}
+
+/// Why the server refused a [`ClientMessages::SetProfile`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProfileRejection {
+    NameLength,
+    /// Names may only use letters, digits, spaces, `_`, `-` and `.`
+    NameCharacters,
+    NameNotAllowed,
+    /// Components must be between 0 and 1
+    InvalidColor,
+    /// Too hard to tell apart from another player's color
+    ColorTaken,
+}
+
+impl fmt::Display for ProfileRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileRejection::NameLength => write!(f, "name must be 1 to {} characters", MAX_NAME_CHARS),
+            ProfileRejection::NameCharacters => write!(f, "name may only use letters, digits, spaces, _, - and ."),
+            ProfileRejection::NameNotAllowed => write!(f, "name is not allowed"),
+            ProfileRejection::InvalidColor => write!(f, "color components must be between 0 and 1"),
+            ProfileRejection::ColorTaken => write!(f, "color is too close to another player's"),
+        }
+    }
+}
+
+/// Messages sent by the server on `DefaultChannel::ReliableOrdered`
+#[derive(Debug, Clone, Serialize, Deserialize, Component)]
+pub enum ServerMessages {
+    /// Broadcast when a player joins, its entity follows with [`ServerMessages::SpawnPlayer`] once it is in range
+    PlayerConnected {
+        id: ClientId,
+        color: [f32; 4],
+    },
+    PlayerDisconnected {
+        id: ClientId,
+    },
+    /// Sent to a client when a player comes within its area of interest, its own player included
+    SpawnPlayer {
+        id: ClientId,
+        color: [f32; 4],
+        /// `None` until the player sets a profile
+        name: Option<String>,
+    },
+    /// Sent to a client when a player leaves its area of interest, snapshots stop mentioning it
+    DespawnPlayer {
+        id: ClientId,
+    },
+    /// Sent to a single client right before the server disconnects it
+    ConnectionRejected {
+        reason: RejectReason,
+    },
+    /// Broadcast before the server goes away, `reconnect_hint` is how long to wait before reconnecting if at all
+    ServerShuttingDown {
+        reconnect_hint: Option<Duration>,
+    },
+    /// Sent only to the joining client, present `token` in the next [`Handshake`] to reclaim the player
+    SessionAssigned {
+        token: SessionToken,
+    },
+    /// Sent to a joining client, it should send one [`InputCommand`] per tick and predict with these settings
+    Simulation {
+        settings: SimulationSettings,
+    },
+    /// Sent to a client whose session is held by another replica, it should reconnect to `address`
+    Redirect {
+        address: SocketAddr,
+    },
+    /// Broadcast when the server's settings are reloaded, clients keep their state and predict with the new settings
+    SimulationChanged {
+        settings: SimulationSettings,
+    },
+    /// Broadcast when a player's [`ClientMessages::SetProfile`] is accepted
+    ProfileChanged {
+        id: ClientId,
+        name: String,
+        color: [f32; 4],
+    },
+    /// Sent only to a client whose [`ClientMessages::SetProfile`] was refused, its profile is unchanged
+    ProfileRejected {
+        reason: ProfileRejection,
+    },
+}
+
+/// Map of connected players to their entity
+#[derive(Debug, Default, Resource)]
+pub struct Lobby {
+    pub players: HashMap<ClientId, Entity>,
+}
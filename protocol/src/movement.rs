@@ -0,0 +1,81 @@
+//! Movement rules shared by the server simulation and client side prediction.
+//!
+//! The server and the clients both call [`move_player`] once per tick with the same inputs, so a client
+//! replaying its unacknowledged inputs on top of an authoritative snapshot ends up where the server will.
+
+use crate::{InputCommand, PlayerInput};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Most unacknowledged inputs a client keeps for replay
+const MAX_PENDING_INPUTS: usize = 256;
+
+/// Simulation parameters the server shares with its clients
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Resource)]
+pub struct SimulationSettings {
+    pub tick_rate: f64,
+    pub player_move_speed: f32,
+}
+
+impl SimulationSettings {
+    /// Length of one tick in seconds
+    pub fn tick_seconds(&self) -> f32 {
+        (1.0 / self.tick_rate) as f32
+    }
+
+    /// Server time in seconds at the start of `tick`
+    pub fn tick_time(&self, tick: u32) -> f64 {
+        tick as f64 / self.tick_rate
+    }
+}
+
+/// Advance a player by one tick of input, in server space where players move on the x/z plane
+pub fn move_player(translation: &mut Vec3, input: &PlayerInput, player_move_speed: f32, delta_seconds: f32) {
+    let x = (input.right as i8 - input.left as i8) as f32;
+    let z = (input.down as i8 - input.up as i8) as f32;
+    translation.x += x * player_move_speed * delta_seconds;
+    translation.z += z * player_move_speed * delta_seconds;
+}
+
+/// Predicted position of the local player and the inputs the server has not acknowledged yet
+#[derive(Debug, Default, Resource)]
+pub struct Prediction {
+    /// `None` until the first authoritative snapshot of the local player arrives
+    pub translation: Option<Vec3>,
+    pending: VecDeque<InputCommand>,
+}
+
+impl Prediction {
+    /// Apply an input locally as soon as it is sent
+    pub fn predict(&mut self, command: InputCommand, settings: &SimulationSettings) {
+        if let Some(translation) = self.translation.as_mut() {
+            move_player(translation, &command.input, settings.player_move_speed, settings.tick_seconds());
+        }
+        if self.pending.len() >= MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(command);
+    }
+
+    /// Restart from the server's position and replay the inputs it has not processed yet
+    pub fn reconcile(&mut self, server_translation: Vec3, last_input_sequence: u32, settings: &SimulationSettings) {
+        self.pending.retain(|command| command.sequence > last_input_sequence);
+        let mut translation = server_translation;
+        for command in &self.pending {
+            move_player(
+                &mut translation,
+                &command.input,
+                settings.player_move_speed,
+                settings.tick_seconds(),
+            );
+        }
+        self.translation = Some(translation);
+    }
+
+    /// Forget everything, used when the connection is replaced
+    pub fn reset(&mut self) {
+        self.translation = None;
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,242 @@
+//! Network condition simulator, a UDP relay that delays, drops, reorders and duplicates packets.
+//!
+//! Local runs have next to no latency, which hides what players see on real networks. A relay sits in
+//! front of a transport's socket and impairs every packet going through it, in both directions, so the
+//! netcode transports are used unchanged. [`bind_server_socket`] puts a relay in front of a server and
+//! [`route_client`] puts one between a client and its server.
+
+use bevy_renet::netcode::{ClientAuthentication, generate_random_bytes};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::env;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Extra delay of packets picked for reordering, so packets sent after them overtake them
+pub const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Peers silent for this long are forgotten, and a client relay stops once its client is
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the relay sleeps when no packet arrived
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Larger than any netcode packet
+const MAX_PACKET_BYTES: usize = 2048;
+
+/// How packets are impaired, in each direction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to every packet
+    pub latency: Duration,
+    /// Random extra delay of up to this much, packets overtaking each other arrive reordered
+    pub jitter: Duration,
+    /// Chance of dropping a packet, from 0 to 1
+    pub loss: f64,
+    /// Chance of delivering a packet twice
+    pub duplication: f64,
+    /// Chance of holding a packet back by [`REORDER_DELAY`]
+    pub reorder: f64,
+}
+
+impl NetworkConditions {
+    /// Read `NET_LATENCY_MS`, `NET_JITTER_MS`, `NET_LOSS_PERCENT`, `NET_DUPLICATE_PERCENT` and `NET_REORDER_PERCENT`.
+    ///
+    /// Returns `None` when none of them are set, so the network is left alone.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| env::var(name).ok().and_then(|s| s.parse::<f64>().ok()).unwrap_or(0.0);
+        let conditions = Self {
+            latency: Duration::from_secs_f64(var("NET_LATENCY_MS").max(0.0) / 1000.0),
+            jitter: Duration::from_secs_f64(var("NET_JITTER_MS").max(0.0) / 1000.0),
+            loss: (var("NET_LOSS_PERCENT") / 100.0).clamp(0.0, 1.0),
+            duplication: (var("NET_DUPLICATE_PERCENT") / 100.0).clamp(0.0, 1.0),
+            reorder: (var("NET_REORDER_PERCENT") / 100.0).clamp(0.0, 1.0),
+        };
+        (conditions != Self::default()).then_some(conditions)
+    }
+}
+
+/// Bind a server's UDP port behind a relay applying `conditions`, returns the socket the transport should use.
+///
+/// The transport sees every client at a local address of the relay instead of its real one.
+pub fn bind_server_socket(addr: SocketAddr, conditions: NetworkConditions) -> io::Result<UdpSocket> {
+    let listen = UdpSocket::bind(addr)?;
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    spawn_relay(listen, socket.local_addr()?, conditions, false)?;
+    Ok(socket)
+}
+
+/// Send a client's connection through a relay applying `conditions`, returns the authentication to connect with instead
+pub fn route_client(authentication: ClientAuthentication, conditions: NetworkConditions) -> io::Result<ClientAuthentication> {
+    let listen = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let relay_addr = listen.local_addr()?;
+    Ok(match authentication {
+        ClientAuthentication::Unsecure {
+            client_id,
+            protocol_id,
+            server_addr,
+            user_data,
+        } => {
+            spawn_relay(listen, server_addr, conditions, true)?;
+            // Unsecured servers do not check which address the client was told to use
+            ClientAuthentication::Unsecure {
+                client_id,
+                protocol_id,
+                server_addr: relay_addr,
+                user_data,
+            }
+        }
+        ClientAuthentication::Secure { mut connect_token } => {
+            let server_addr = connect_token.server_addresses[0]
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "connect token lists no server"))?;
+            spawn_relay(listen, server_addr, conditions, true)?;
+            // Servers check the encrypted copy of the address list, only the public one tells the client where to go
+            connect_token.server_addresses[0] = Some(relay_addr);
+            ClientAuthentication::Secure { connect_token }
+        }
+    })
+}
+
+/// Where a queued packet goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Hop {
+    /// From a peer to the target, through the peer's upstream socket
+    ToTarget(SocketAddr),
+    /// From the target back to a peer, through the listening socket
+    ToPeer(SocketAddr),
+}
+
+/// Packets waiting for their delivery time, earliest first, ties broken by arrival
+type Queue = BinaryHeap<(Reverse<(Instant, u64)>, Hop, Vec<u8>)>;
+
+struct Relay {
+    listen: UdpSocket,
+    target: SocketAddr,
+    conditions: NetworkConditions,
+    /// One socket per peer towards the target, so the target tells peers apart by address, with when the peer was last heard
+    upstreams: HashMap<SocketAddr, (UdpSocket, Instant)>,
+    queue: Queue,
+    sequence: u64,
+    rng: u64,
+}
+
+fn spawn_relay(listen: UdpSocket, target: SocketAddr, conditions: NetworkConditions, stop_when_idle: bool) -> io::Result<()> {
+    listen.set_nonblocking(true)?;
+    let relay = Relay {
+        listen,
+        target,
+        conditions,
+        upstreams: HashMap::new(),
+        queue: Queue::new(),
+        sequence: 0,
+        // Xorshift never leaves zero
+        rng: u64::from_le_bytes(generate_random_bytes()).max(1),
+    };
+    thread::spawn(move || relay.run(stop_when_idle));
+    Ok(())
+}
+
+impl Relay {
+    fn run(mut self, stop_when_idle: bool) {
+        let mut buffer = [0; MAX_PACKET_BYTES];
+        let mut last_heard = Instant::now();
+        loop {
+            let now = Instant::now();
+            let mut received = false;
+
+            while let Ok((len, peer)) = self.listen.recv_from(&mut buffer) {
+                received = true;
+                last_heard = now;
+                if !self.upstreams.contains_key(&peer) {
+                    match self.bind_upstream() {
+                        Ok(socket) => {
+                            self.upstreams.insert(peer, (socket, now));
+                        }
+                        Err(e) => {
+                            eprintln!("Network simulator failed to relay {}: {}", peer, e);
+                            continue;
+                        }
+                    }
+                }
+                if let Some((_, heard)) = self.upstreams.get_mut(&peer) {
+                    *heard = now;
+                }
+                self.schedule(Hop::ToTarget(peer), &buffer[..len], now);
+            }
+
+            let mut replies = Vec::new();
+            for (&peer, (socket, _)) in &self.upstreams {
+                while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                    if from == self.target {
+                        replies.push((peer, buffer[..len].to_vec()));
+                    }
+                }
+            }
+            for (peer, payload) in replies {
+                received = true;
+                self.schedule(Hop::ToPeer(peer), &payload, now);
+            }
+
+            while self.queue.peek().is_some_and(|(Reverse((due, _)), _, _)| *due <= now) {
+                let (_, hop, payload) = self.queue.pop().unwrap();
+                // Delivery is best effort, like the network being simulated
+                let _ = match hop {
+                    Hop::ToTarget(peer) => match self.upstreams.get(&peer) {
+                        Some((socket, _)) => socket.send_to(&payload, self.target),
+                        None => continue,
+                    },
+                    Hop::ToPeer(peer) => self.listen.send_to(&payload, peer),
+                };
+            }
+
+            self.upstreams.retain(|_, (_, heard)| now.duration_since(*heard) < PEER_TIMEOUT);
+            if stop_when_idle && now.duration_since(last_heard) > PEER_TIMEOUT {
+                return;
+            }
+            if !received {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn bind_upstream(&self) -> io::Result<UdpSocket> {
+        let socket = if self.target.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    /// Queue a packet with its simulated delay, unless it is lost
+    fn schedule(&mut self, hop: Hop, payload: &[u8], now: Instant) {
+        let conditions = self.conditions;
+        if self.chance(conditions.loss) {
+            return;
+        }
+        let copies = if self.chance(conditions.duplication) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay = conditions.latency + conditions.jitter.mul_f64(self.random());
+            if self.chance(conditions.reorder) {
+                delay += REORDER_DELAY;
+            }
+            self.sequence += 1;
+            self.queue.push((Reverse((now + delay, self.sequence)), hop, payload.to_vec()));
+        }
+    }
+
+    /// Uniform in `[0, 1)`
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.random() < probability
+    }
+}
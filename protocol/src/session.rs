@@ -0,0 +1,43 @@
+//! Session tokens that let a client reclaim its player after reconnecting.
+//!
+//! The server hands out a fresh [`SessionToken`] every time a client joins. The client stores it on disk and
+//! sends it back in its [`Handshake`](crate::Handshake), so the server can re-bind the new connection to the
+//! player left behind during the disconnect grace period, even if the client id changed.
+
+use crate::hex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Number of random bytes in a [`SessionToken`]
+pub const SESSION_TOKEN_BYTES: usize = 16;
+
+/// Secret identifying a player across connections
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken(pub [u8; SESSION_TOKEN_BYTES]);
+
+impl SessionToken {
+    /// Read a token saved with [`SessionToken::save`], `None` if missing or unreadable
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        hex::decode(&contents).map(Self)
+    }
+
+    /// Save the token so it survives a client restart
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_hex())
+    }
+
+    /// Encode the token as lowercase hex, for servers that key shared state by session
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+}
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionToken(<redacted>)")
+    }
+}
@@ -0,0 +1,181 @@
+//! Delta compressed world snapshots.
+//!
+//! The server remembers the snapshots it sent to each client and encodes every new one against the newest
+//! snapshot that client acknowledged with a [`SnapshotAck`]. Only players whose quantized position changed since
+//! that baseline are sent, so idle players cost nothing. Without a usable baseline the full state is sent instead.
+//! Positions are sent as offsets from the baseline with variable length integers, so a player that moved a little
+//! costs a few bytes instead of twelve.
+//! Colors are not part of snapshots, clients get them once from
+//! [`ServerMessages::PlayerConnected`](crate::ServerMessages::PlayerConnected).
+
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Snapshots remembered on each side, about a second at 60Hz. Older baselines fall back to a full snapshot.
+const SNAPSHOT_HISTORY: usize = 64;
+
+/// Positions are sent in whole hundredths of a unit, so unchanged positions compare equal exactly.
+/// The 2D client works in pixels, so the range has to go well past the screen edges.
+pub const POSITION_SCALE: f32 = 100.0;
+
+/// Largest snapshot, in bytes, that [`DeltaSnapshot::from_bytes`] will read.
+/// Snapshots grow with the players in view, so they get more room than other messages.
+pub const MAX_SNAPSHOT_SIZE: u64 = 64 * 1024;
+
+/// Position rounded to the precision sent on the wire, see [`quantize`]
+pub type QuantizedPosition = [i32; 3];
+
+/// Quantized position of every player at one tick
+pub type WorldState = HashMap<ClientId, QuantizedPosition>;
+
+/// Round a translation to the precision sent on the wire
+pub fn quantize(translation: Vec3) -> QuantizedPosition {
+    translation.to_array().map(|v| (v * POSITION_SCALE).round() as i32)
+}
+
+/// Translation of a position produced by [`quantize`]
+pub fn dequantize(position: QuantizedPosition) -> Vec3 {
+    Vec3::from_array(position.map(|v| v as f32 / POSITION_SCALE))
+}
+
+/// `position` relative to `origin`
+fn offset(position: QuantizedPosition, origin: QuantizedPosition) -> QuantizedPosition {
+    std::array::from_fn(|i| position[i].wrapping_sub(origin[i]))
+}
+
+/// Snapshot sent by the server to a single client on `DefaultChannel::Unreliable` every tick,
+/// encoded with [`DeltaSnapshot::to_bytes`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaSnapshot {
+    /// Server tick the snapshot was taken on
+    pub tick: u32,
+    /// Tick of the acknowledged state this snapshot is relative to, `None` for a full snapshot
+    pub baseline: Option<u32>,
+    /// Sequence of the last [`InputCommand`](crate::InputCommand) from the receiving client applied before the snapshot was taken
+    pub last_input_sequence: u32,
+    /// Players that joined or moved since the baseline, as offsets from their position in the baseline,
+    /// or from the origin for players that are not in it
+    pub changed: Vec<(ClientId, QuantizedPosition)>,
+    /// Players in the baseline that are gone
+    pub removed: Vec<ClientId>,
+}
+
+/// Snapshots use variable length integers, unlike every other message, so small offsets take a single byte
+fn wire_options() -> impl Options {
+    bincode::DefaultOptions::new().with_varint_encoding()
+}
+
+impl DeltaSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        wire_options().serialize(self).unwrap()
+    }
+
+    /// Decode a snapshot from [`DeltaSnapshot::to_bytes`], without trusting its contents like [`crate::decode`]
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        if bytes.len() as u64 > MAX_SNAPSHOT_SIZE {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        wire_options().with_limit(MAX_SNAPSHOT_SIZE).deserialize(bytes)
+    }
+}
+
+/// Sent by a client on `DefaultChannel::Unreliable` for each snapshot it decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotAck {
+    pub tick: u32,
+}
+
+/// Whether `tick` comes after `other`, allowing for the tick counter wrapping around
+fn is_newer(tick: u32, other: u32) -> bool {
+    (tick.wrapping_sub(other) as i32) > 0
+}
+
+/// Server side record of the snapshots sent to one client
+#[derive(Debug, Default)]
+pub struct SnapshotSender {
+    sent: VecDeque<(u32, WorldState)>,
+    acked: Option<u32>,
+}
+
+impl SnapshotSender {
+    /// Use the snapshot from `tick` as the baseline for the next ones, unless a newer one was acknowledged already
+    pub fn ack(&mut self, tick: u32) {
+        if self.acked.is_none_or(|acked| is_newer(tick, acked)) && self.sent.iter().any(|&(sent, _)| sent == tick) {
+            self.acked = Some(tick);
+        }
+    }
+
+    /// Encode `state` against the newest acknowledged snapshot and remember it as a future baseline
+    pub fn encode(&mut self, tick: u32, state: &WorldState, last_input_sequence: u32) -> DeltaSnapshot {
+        let baseline = self.acked.and_then(|acked| self.sent.iter().find(|&&(sent, _)| sent == acked));
+        let snapshot = DeltaSnapshot {
+            tick,
+            baseline: baseline.map(|&(baseline_tick, _)| baseline_tick),
+            last_input_sequence,
+            changed: state
+                .iter()
+                .filter_map(|(&id, &position)| {
+                    let previous = baseline.and_then(|(_, previous)| previous.get(&id)).copied();
+                    (previous != Some(position)).then(|| (id, offset(position, previous.unwrap_or_default())))
+                })
+                .collect(),
+            removed: baseline
+                .map(|(_, previous)| previous.keys().filter(|id| !state.contains_key(id)).copied().collect())
+                .unwrap_or_default(),
+        };
+
+        if self.sent.len() >= SNAPSHOT_HISTORY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((tick, state.clone()));
+        snapshot
+    }
+}
+
+/// Client side record of decoded states, which later snapshots may be relative to
+#[derive(Debug, Default, Resource)]
+pub struct SnapshotReceiver {
+    states: VecDeque<(u32, WorldState)>,
+}
+
+impl SnapshotReceiver {
+    /// Rebuild the full state from a snapshot.
+    ///
+    /// Returns `None` when the snapshot is older than the newest one decoded, or its baseline is no longer known.
+    /// Only snapshots that return a state should be acknowledged.
+    pub fn receive(&mut self, snapshot: &DeltaSnapshot) -> Option<&WorldState> {
+        if self.states.back().is_some_and(|&(newest, _)| !is_newer(snapshot.tick, newest)) {
+            return None;
+        }
+        let mut state = match snapshot.baseline {
+            Some(baseline) => self.states.iter().find(|&&(tick, _)| tick == baseline)?.1.clone(),
+            None => WorldState::new(),
+        };
+        for id in &snapshot.removed {
+            state.remove(id);
+        }
+        for &(id, change) in &snapshot.changed {
+            let previous = state.get(&id).copied().unwrap_or_default();
+            state.insert(id, std::array::from_fn(|i| previous[i].wrapping_add(change[i])));
+        }
+
+        if self.states.len() >= SNAPSHOT_HISTORY {
+            self.states.pop_front();
+        }
+        self.states.push_back((snapshot.tick, state));
+        self.states.back().map(|(_, state)| state)
+    }
+
+    /// Tick and state of the newest snapshot decoded
+    pub fn newest(&self) -> Option<(u32, &WorldState)> {
+        self.states.back().map(|(tick, state)| (*tick, state))
+    }
+
+    /// Forget every state, used when connecting to a new server
+    pub fn reset(&mut self) {
+        self.states.clear();
+    }
+}
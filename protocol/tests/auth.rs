@@ -0,0 +1,60 @@
+use bevy_renet::netcode::{ConnectToken, NETCODE_KEY_BYTES};
+use protocol::auth::{PrivateKey, TokenRequest, read_token_request, request_connect_token};
+use protocol::{Handshake, PROTOCOL_ID};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn private_key_hex_roundtrip() {
+    let key = PrivateKey([0xab; NETCODE_KEY_BYTES]);
+    assert_eq!(PrivateKey::from_hex(&key.to_hex()), Some(key));
+}
+
+#[test]
+fn private_key_rejects_bad_hex() {
+    assert_eq!(PrivateKey::from_hex("abcd"), None);
+    assert_eq!(PrivateKey::from_hex(&"zz".repeat(NETCODE_KEY_BYTES)), None);
+}
+
+#[test]
+fn private_key_debug_is_redacted() {
+    let key = PrivateKey([0xab; NETCODE_KEY_BYTES]);
+    assert!(!format!("{:?}", key).contains("ab"));
+}
+
+#[test]
+fn connect_token_over_localhost() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let auth_addr = listener.local_addr().unwrap();
+    let key = PrivateKey([7; NETCODE_KEY_BYTES]);
+    let server_addr = "127.0.0.1:5000".parse().unwrap();
+
+    let service = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_token_request(&mut stream).unwrap();
+        let token = ConnectToken::generate(
+            Duration::ZERO,
+            PROTOCOL_ID,
+            300,
+            42,
+            15,
+            vec![server_addr],
+            Some(&request.handshake.to_user_data()),
+            &key.0,
+        )
+        .unwrap();
+        token.write(&mut stream).unwrap();
+    });
+
+    let request = TokenRequest {
+        handshake: Handshake::current(),
+        preferred_server: None,
+    };
+    let token = request_connect_token(auth_addr, &request).unwrap();
+    service.join().unwrap();
+
+    assert_eq!(token.client_id, 42);
+    assert_eq!(token.protocol_id, PROTOCOL_ID);
+    assert_eq!(token.server_addresses[0], Some(server_addr));
+}
@@ -0,0 +1,107 @@
+use protocol::{ClientMessages, InputCommand, MAX_MESSAGE_SIZE, PlayerInput, decode};
+
+/// Small xorshift generator so the fuzzed inputs are reproducible without extra dependencies
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+#[test]
+fn decodes_valid_input() {
+    let input = PlayerInput {
+        up: true,
+        down: false,
+        left: true,
+        right: false,
+    };
+    let bytes = bincode::serialize(&input).unwrap();
+    let decoded: PlayerInput = decode(&bytes).unwrap();
+    assert_eq!((decoded.up, decoded.down, decoded.left, decoded.right), (true, false, true, false));
+}
+
+#[test]
+fn decodes_valid_input_command() {
+    let command = InputCommand {
+        sequence: 42,
+        input: PlayerInput {
+            right: true,
+            ..Default::default()
+        },
+    };
+    let bytes = bincode::serialize(&command).unwrap();
+    let decoded: InputCommand = decode(&bytes).unwrap();
+    assert_eq!(decoded.sequence, 42);
+    assert!(decoded.input.right);
+}
+
+#[test]
+fn decodes_valid_set_profile() {
+    let message = ClientMessages::SetProfile {
+        name: "Ferris".to_string(),
+        preferred_color: Some([1.0, 0.5, 0.0, 1.0]),
+    };
+    let bytes = bincode::serialize(&message).unwrap();
+    let ClientMessages::SetProfile { name, preferred_color } = decode(&bytes).unwrap() else {
+        panic!("decoded a different message");
+    };
+    assert_eq!(name, "Ferris");
+    assert_eq!(preferred_color, Some([1.0, 0.5, 0.0, 1.0]));
+}
+
+#[test]
+fn rejects_oversized_payload() {
+    let bytes = vec![0; MAX_MESSAGE_SIZE as usize + 1];
+    assert!(decode::<PlayerInput>(&bytes).is_err());
+}
+
+#[test]
+fn rejects_trailing_bytes() {
+    let mut bytes = bincode::serialize(&PlayerInput::default()).unwrap();
+    bytes.push(0);
+    assert!(decode::<PlayerInput>(&bytes).is_err());
+}
+
+#[test]
+fn rejects_truncated_input() {
+    let bytes = bincode::serialize(&PlayerInput::default()).unwrap();
+    for len in 0..bytes.len() {
+        assert!(decode::<PlayerInput>(&bytes[..len]).is_err());
+    }
+}
+
+#[test]
+fn survives_fuzzed_input() {
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..100_000 {
+        let len = (rng.next() % (MAX_MESSAGE_SIZE * 2)) as usize;
+        let bytes = rng.bytes(len);
+        let _ = decode::<PlayerInput>(&bytes);
+        let _ = decode::<InputCommand>(&bytes);
+        let _ = decode::<ClientMessages>(&bytes);
+    }
+}
+
+#[test]
+fn survives_mutated_input() {
+    let mut rng = XorShift(0xD1B5_4A32_D192_ED03);
+    let valid = bincode::serialize(&PlayerInput::default()).unwrap();
+    for _ in 0..100_000 {
+        let mut bytes = valid.clone();
+        let index = rng.next() as usize % bytes.len();
+        bytes[index] = rng.next() as u8;
+        if let Ok(input) = decode::<PlayerInput>(&bytes) {
+            // Only 0 and 1 are valid bools, anything else must be rejected
+            assert!(bytes.iter().all(|&b| b <= 1), "decoded {:?} from {:?}", input, bytes);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use bevy::math::Vec3;
+use protocol::interpolation::{ServerClock, SnapshotBuffer};
+
+#[test]
+fn empty_buffer_has_no_position() {
+    assert_eq!(SnapshotBuffer::default().sample(1.0, 0.25), None);
+}
+
+#[test]
+fn interpolates_between_snapshots() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(1.0, Vec3::ZERO);
+    buffer.push(1.1, Vec3::new(1.0, 0.0, 0.0));
+    buffer.push(1.2, Vec3::new(2.0, 0.0, 0.0));
+    assert!(buffer.sample(1.15, 0.25).unwrap().distance(Vec3::new(1.5, 0.0, 0.0)) < 1e-4);
+}
+
+#[test]
+fn holds_oldest_snapshot_before_render_time() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(1.0, Vec3::ONE);
+    buffer.push(1.1, Vec3::ZERO);
+    assert_eq!(buffer.sample(0.5, 0.25), Some(Vec3::ONE));
+}
+
+#[test]
+fn extrapolation_is_limited() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(1.0, Vec3::ZERO);
+    buffer.push(1.1, Vec3::new(1.0, 0.0, 0.0));
+    assert!(buffer.sample(1.2, 0.25).unwrap().distance(Vec3::new(2.0, 0.0, 0.0)) < 1e-4);
+    // Packets stopped arriving a long time ago, stay where the limit put us
+    assert!(buffer.sample(5.0, 0.25).unwrap().distance(Vec3::new(3.5, 0.0, 0.0)) < 1e-4);
+}
+
+#[test]
+fn ignores_out_of_order_snapshots() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(1.0, Vec3::ZERO);
+    buffer.push(1.2, Vec3::new(2.0, 0.0, 0.0));
+    buffer.push(1.1, Vec3::new(100.0, 0.0, 0.0));
+    assert!(buffer.sample(1.1, 0.25).unwrap().distance(Vec3::new(1.0, 0.0, 0.0)) < 1e-4);
+}
+
+#[test]
+fn server_clock_tracks_offset() {
+    let mut clock = ServerClock::default();
+    assert_eq!(clock.server_time(0.0), None);
+    clock.observe(100.0, 10.0);
+    assert_eq!(clock.server_time(11.0), Some(101.0));
+    clock.reset();
+    assert_eq!(clock.server_time(11.0), None);
+}
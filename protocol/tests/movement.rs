@@ -0,0 +1,61 @@
+use bevy::math::Vec3;
+use protocol::movement::{Prediction, SimulationSettings, move_player};
+use protocol::{InputCommand, PlayerInput};
+
+const SETTINGS: SimulationSettings = SimulationSettings {
+    tick_rate: 60.0,
+    player_move_speed: 6.0,
+};
+
+fn command(sequence: u32) -> InputCommand {
+    InputCommand {
+        sequence,
+        input: PlayerInput {
+            right: true,
+            up: sequence.is_multiple_of(2),
+            ..Default::default()
+        },
+    }
+}
+
+#[test]
+fn no_prediction_before_first_snapshot() {
+    let mut prediction = Prediction::default();
+    prediction.predict(command(1), &SETTINGS);
+    assert_eq!(prediction.translation, None);
+}
+
+#[test]
+fn reconcile_replays_unacknowledged_inputs() {
+    let mut prediction = Prediction::default();
+    prediction.reconcile(Vec3::ZERO, 0, &SETTINGS);
+    for sequence in 1..=10 {
+        prediction.predict(command(sequence), &SETTINGS);
+    }
+
+    // The server has applied the first four inputs
+    let mut server = Vec3::ZERO;
+    for sequence in 1..=4 {
+        move_player(
+            &mut server,
+            &command(sequence).input,
+            SETTINGS.player_move_speed,
+            SETTINGS.tick_seconds(),
+        );
+    }
+    let predicted = prediction.translation.unwrap();
+    prediction.reconcile(server, 4, &SETTINGS);
+    assert!(prediction.translation.unwrap().distance(predicted) < 1e-4);
+}
+
+#[test]
+fn reconcile_corrects_misprediction() {
+    let mut prediction = Prediction::default();
+    prediction.reconcile(Vec3::ZERO, 0, &SETTINGS);
+    prediction.predict(command(1), &SETTINGS);
+
+    // The server put the player somewhere else, e.g. after a collision
+    let server = Vec3::new(-3.0, 0.0, 2.0);
+    prediction.reconcile(server, 1, &SETTINGS);
+    assert_eq!(prediction.translation, Some(server));
+}
@@ -0,0 +1,119 @@
+use bevy_renet::netcode::ClientAuthentication;
+use protocol::PROTOCOL_ID;
+use protocol::netsim::{NetworkConditions, bind_server_socket, route_client};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// A server socket behind a relay on a free port, with the address clients send to
+fn relayed_server(conditions: NetworkConditions) -> (UdpSocket, SocketAddr) {
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let socket = bind_server_socket(addr, conditions).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    (socket, addr)
+}
+
+fn client_socket() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    socket
+}
+
+/// Every packet that arrives before the read timeout
+fn receive_all(socket: &UdpSocket) -> Vec<(Vec<u8>, SocketAddr)> {
+    let mut buffer = [0; 64];
+    let mut packets = Vec::new();
+    while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+        packets.push((buffer[..len].to_vec(), from));
+    }
+    packets
+}
+
+#[test]
+fn latency_is_added_in_both_directions() {
+    let latency = Duration::from_millis(40);
+    let (server, addr) = relayed_server(NetworkConditions {
+        latency,
+        ..Default::default()
+    });
+    let client = client_socket();
+    let sent = Instant::now();
+    client.send_to(b"ping", addr).unwrap();
+
+    let mut buffer = [0; 64];
+    let (len, relayed_from) = server.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"ping");
+    assert!(sent.elapsed() >= latency);
+    // The server answers the relay, which passes the answer on from the address the client used
+    server.send_to(b"pong", relayed_from).unwrap();
+    let (len, from) = client.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"pong");
+    assert_eq!(from, addr);
+    assert!(sent.elapsed() >= latency * 2);
+}
+
+#[test]
+fn clients_are_told_apart() {
+    let (server, addr) = relayed_server(NetworkConditions {
+        jitter: Duration::from_millis(5),
+        ..Default::default()
+    });
+    let (a, b) = (client_socket(), client_socket());
+    a.send_to(b"a", addr).unwrap();
+    b.send_to(b"b", addr).unwrap();
+    let packets = receive_all(&server);
+    assert_eq!(packets.len(), 2);
+    assert_ne!(packets[0].1, packets[1].1);
+}
+
+#[test]
+fn lost_packets_never_arrive() {
+    let (server, addr) = relayed_server(NetworkConditions {
+        loss: 1.0,
+        ..Default::default()
+    });
+    let client = client_socket();
+    for _ in 0..10 {
+        client.send_to(b"lost", addr).unwrap();
+    }
+    assert!(receive_all(&server).is_empty());
+}
+
+#[test]
+fn duplicated_packets_arrive_twice() {
+    let (server, addr) = relayed_server(NetworkConditions {
+        duplication: 1.0,
+        ..Default::default()
+    });
+    client_socket().send_to(b"twice", addr).unwrap();
+    let packets = receive_all(&server);
+    assert_eq!(packets.len(), 2);
+    assert!(packets.iter().all(|(payload, _)| payload == b"twice"));
+}
+
+#[test]
+fn unsecure_client_is_routed_through_relay() {
+    let server = client_socket();
+    let server_addr = server.local_addr().unwrap();
+    let authentication = ClientAuthentication::Unsecure {
+        client_id: 1,
+        protocol_id: PROTOCOL_ID,
+        server_addr,
+        user_data: None,
+    };
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let ClientAuthentication::Unsecure {
+        server_addr: relay_addr, ..
+    } = route_client(authentication, conditions).unwrap()
+    else {
+        panic!("the authentication kind changed");
+    };
+    assert_ne!(relay_addr, server_addr);
+
+    client_socket().send_to(b"hello", relay_addr).unwrap();
+    let packets = receive_all(&server);
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].0, b"hello");
+}
@@ -0,0 +1,35 @@
+use protocol::session::{SESSION_TOKEN_BYTES, SessionToken};
+use protocol::{Handshake, PaletteMode};
+use std::env;
+use std::fs;
+
+#[test]
+fn session_token_file_roundtrip() {
+    let path = env::temp_dir().join(format!("session-roundtrip-{}", std::process::id()));
+    let token = SessionToken([3; SESSION_TOKEN_BYTES]);
+    token.save(&path).unwrap();
+    assert_eq!(SessionToken::load(&path), Some(token));
+    fs::remove_file(&path).unwrap();
+    assert_eq!(SessionToken::load(&path), None);
+}
+
+#[test]
+fn handshake_carries_session_in_user_data() {
+    let handshake = Handshake {
+        session: Some(SessionToken([9; SESSION_TOKEN_BYTES])),
+        take_over: true,
+        ..Handshake::current()
+    };
+    assert_eq!(Handshake::from_user_data(&handshake.to_user_data()), Some(handshake));
+}
+
+#[test]
+fn handshake_carries_palette_in_user_data() {
+    let handshake = Handshake {
+        palette: Some("high-contrast".parse().unwrap()),
+        ..Handshake::current()
+    };
+    assert_eq!(handshake.palette, Some(PaletteMode::HighContrast));
+    assert_eq!(Handshake::from_user_data(&handshake.to_user_data()), Some(handshake));
+    assert!("tritanopia".parse::<PaletteMode>().is_err());
+}
@@ -0,0 +1,199 @@
+use bevy::math::Vec3;
+use bevy_renet::renet::ClientId;
+use protocol::snapshot::{DeltaSnapshot, MAX_SNAPSHOT_SIZE, SnapshotReceiver, SnapshotSender, WorldState, dequantize, quantize};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Small xorshift generator so the simulated packet loss is reproducible without extra dependencies
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+/// Per player state of the full snapshot the server used to broadcast every tick
+#[derive(Serialize)]
+struct FullPlayerSnapshot {
+    translation: [f32; 3],
+    color: [f32; 4],
+    last_input_sequence: u32,
+}
+
+/// Full snapshot the server used to broadcast every tick
+#[derive(Serialize)]
+struct FullSnapshots {
+    tick: u32,
+    players: HashMap<ClientId, FullPlayerSnapshot>,
+}
+
+fn state(players: &[(ClientId, Vec3)]) -> WorldState {
+    players.iter().map(|&(id, translation)| (id, quantize(translation))).collect()
+}
+
+#[test]
+fn quantize_roundtrip() {
+    let translation = Vec3::new(1.234, 0.5, -3.456);
+    assert!(dequantize(quantize(translation)).distance(translation) < 0.01);
+    assert_eq!(quantize(Vec3::new(1280.0, 0.0, -720.0)), [128_000, 0, -72_000]);
+}
+
+#[test]
+fn full_snapshot_until_acked() {
+    let mut sender = SnapshotSender::default();
+    let world = state(&[(1, Vec3::ZERO), (2, Vec3::ONE)]);
+    for tick in 1..=3 {
+        let snapshot = sender.encode(tick, &world, 0);
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(snapshot.changed.len(), 2);
+    }
+}
+
+#[test]
+fn only_changes_after_ack() {
+    let mut sender = SnapshotSender::default();
+    let mut receiver = SnapshotReceiver::default();
+    let first = state(&[(1, Vec3::ZERO), (2, Vec3::ONE), (3, Vec3::X)]);
+    assert_eq!(receiver.receive(&sender.encode(1, &first, 0)), Some(&first));
+    sender.ack(1);
+
+    let second = state(&[(1, Vec3::ZERO), (2, Vec3::new(1.5, 1.0, 1.0))]);
+    let snapshot = sender.encode(2, &second, 7);
+    assert_eq!(snapshot.baseline, Some(1));
+    // Relative to where the baseline had the player
+    assert_eq!(snapshot.changed, vec![(2, [50, 0, 0])]);
+    assert_eq!(snapshot.removed, vec![3]);
+    assert_eq!(snapshot.last_input_sequence, 7);
+    assert_eq!(receiver.receive(&snapshot), Some(&second));
+}
+
+#[test]
+fn receiver_drops_unknown_baseline_and_stale_snapshots() {
+    let mut sender = SnapshotSender::default();
+    let mut receiver = SnapshotReceiver::default();
+    let world = state(&[(1, Vec3::ZERO)]);
+    let lost = sender.encode(1, &world, 0);
+    sender.ack(1);
+    let delta = sender.encode(2, &world, 0);
+    assert_eq!(receiver.receive(&delta), None);
+
+    let full = SnapshotSender::default().encode(3, &world, 0);
+    assert!(receiver.receive(&full).is_some());
+    assert_eq!(receiver.receive(&lost), None);
+    assert_eq!(receiver.newest(), Some((3, &world)));
+}
+
+#[test]
+fn moving_players_take_fewer_bytes_than_floats() {
+    const PLAYERS: u64 = 32;
+    // Ids as handed out by the token service, and positions in the 2D client's pixel space
+    let ids: Vec<ClientId> = (0..PLAYERS).map(|i| 0x1234_5678_9ABC_DEF0 + i).collect();
+    let start: Vec<(ClientId, Vec3)> = ids.iter().map(|&id| (id, Vec3::new(1200.0, -700.0, 0.0))).collect();
+    let mut sender = SnapshotSender::default();
+    sender.encode(1, &state(&start), 0);
+    sender.ack(1);
+    let idle = sender.encode(2, &state(&start), 0).to_bytes().len();
+
+    // A few ticks of running since the acknowledged baseline
+    let moved: Vec<(ClientId, Vec3)> = start.iter().map(|&(id, t)| (id, t + Vec3::new(20.0, -20.0, 0.0))).collect();
+    let snapshot = sender.encode(3, &state(&moved), 0);
+    assert_eq!(snapshot.changed.len(), PLAYERS as usize);
+    let per_player = (snapshot.to_bytes().len() - idle) as u64 / PLAYERS;
+    // What each player cost with its translation as floats
+    let floats = bincode::serialized_size(&(ids[0], [0.0f32; 3])).unwrap();
+    assert!(
+        per_player < floats,
+        "{} bytes per moving player, floats took {}",
+        per_player,
+        floats
+    );
+}
+
+#[test]
+fn oversized_snapshots_are_refused() {
+    assert!(DeltaSnapshot::from_bytes(&vec![0; MAX_SNAPSHOT_SIZE as usize + 1]).is_err());
+    assert!(DeltaSnapshot::from_bytes(&[0xff; 16]).is_err());
+}
+
+#[test]
+fn ignores_ack_for_unsent_tick() {
+    let mut sender = SnapshotSender::default();
+    let world = state(&[(1, Vec3::ZERO)]);
+    sender.encode(1, &world, 0);
+    sender.ack(5);
+    assert_eq!(sender.encode(2, &world, 0).baseline, None);
+}
+
+/// Compare bytes per client per second of the delta snapshots against full snapshots every tick, with
+/// 16 players of which 4 are moving, and 5% of the snapshots and acks lost. Run with `--nocapture` to see the numbers.
+#[test]
+fn bandwidth_benchmark() {
+    const TICK_RATE: u32 = 60;
+    const SECONDS: u32 = 30;
+    const PLAYERS: u64 = 16;
+    const MOVING: u64 = 4;
+    const LOSS_PERCENT: u64 = 5;
+
+    let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+    let mut sender = SnapshotSender::default();
+    let mut receiver = SnapshotReceiver::default();
+    let mut positions: Vec<(ClientId, Vec3)> = (0..PLAYERS).map(|id| (id, Vec3::new(id as f32, 0.5, 0.0))).collect();
+    let mut full_bytes = 0;
+    let mut delta_bytes = 0;
+
+    for tick in 1..=TICK_RATE * SECONDS {
+        for (_, translation) in positions.iter_mut().take(MOVING as usize) {
+            translation.x += 5.0 / TICK_RATE as f32;
+        }
+
+        let full = FullSnapshots {
+            tick,
+            players: positions
+                .iter()
+                .map(|&(id, translation)| {
+                    let snapshot = FullPlayerSnapshot {
+                        translation: translation.into(),
+                        color: [1.0; 4],
+                        last_input_sequence: tick,
+                    };
+                    (id, snapshot)
+                })
+                .collect(),
+        };
+        full_bytes += bincode::serialized_size(&full).unwrap();
+
+        let world = state(&positions);
+        let snapshot = sender.encode(tick, &world, tick);
+        let bytes = snapshot.to_bytes();
+        delta_bytes += bytes.len() as u64;
+        let snapshot = DeltaSnapshot::from_bytes(&bytes).unwrap();
+        if rng.chance(LOSS_PERCENT) {
+            continue;
+        }
+        if let Some(decoded) = receiver.receive(&snapshot) {
+            assert_eq!(decoded, &world, "client state diverged at tick {}", tick);
+            if !rng.chance(LOSS_PERCENT) {
+                sender.ack(tick);
+            }
+        }
+    }
+
+    let full_rate = full_bytes / SECONDS as u64;
+    let delta_rate = delta_bytes / SECONDS as u64;
+    println!("full snapshots: {} bytes per client per second", full_rate);
+    println!("delta snapshots: {} bytes per client per second", delta_rate);
+    assert!(
+        delta_rate * 3 < full_rate,
+        "delta {} is not much smaller than full {}",
+        delta_rate,
+        full_rate
+    );
+}
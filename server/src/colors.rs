@@ -0,0 +1,161 @@
+//! Player colors, picked so every player can tell every other player apart.
+//!
+//! Colors are compared with CIEDE2000, which follows how different two colors look rather than how far
+//! apart their RGB values are. A player who asked for a color-blind-safe palette sees colors through a
+//! simulation of their color vision, and new colors are also compared the way that player sees them.
+
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+use palette::color_difference::Ciede2000;
+use palette::{Clamp, FromColor, Lab, Lch, LinSrgb, Srgb};
+use protocol::PaletteMode;
+use std::collections::HashMap;
+
+/// Colors of the default palette, spread around the hue circle
+const DEFAULT_CANDIDATES: usize = 24;
+
+/// Okabe-Ito followed by Paul Tol's bright scheme, both picked to stay distinct with red-green color blindness
+const COLOR_BLIND_SAFE: [u32; 15] = [
+    0xE69F00, 0x56B4E9, 0x009E73, 0xF0E442, 0x0072B2, 0xD55E00, 0xCC79A7, 0xFFFFFF, 0x4477AA, 0xEE6677, 0x228833, 0xCCBB44, 0x66CCEE,
+    0xAA3377, 0xBBBBBB,
+];
+
+/// Fully saturated primaries and secondaries, with white and black
+const HIGH_CONTRAST: [u32; 9] = [
+    0xFFFFFF, 0xFFFF00, 0x00FFFF, 0xFF00FF, 0xFF0000, 0x0000FF, 0x00FF00, 0xFF8000, 0x000000,
+];
+
+/// Machado, Oliveira and Fernandes 2009 simulation of full deuteranopia, in linear RGB
+const DEUTERANOPIA: [[f32; 3]; 3] = [
+    [0.367322, 0.860646, -0.227968],
+    [0.280085, 0.672501, 0.047413],
+    [-0.011820, 0.042940, 0.968881],
+];
+
+/// Machado, Oliveira and Fernandes 2009 simulation of full protanopia, in linear RGB
+const PROTANOPIA: [[f32; 3]; 3] = [
+    [0.152286, 1.052583, -0.204868],
+    [0.114503, 0.786281, 0.099216],
+    [-0.003882, -0.048116, 1.051998],
+];
+
+/// Colors a new player may get in `mode`, in the order they are tried
+pub fn candidates(mode: PaletteMode) -> Vec<[f32; 4]> {
+    match mode {
+        PaletteMode::Default => (0..DEFAULT_CANDIDATES)
+            .map(|i| {
+                // Golden angle steps keep consecutive colors far apart, alternating lightness and chroma adds variety
+                let hue = (30.0 + i as f32 * 137.508) % 360.0;
+                let (lightness, chroma) = [(60.0, 60.0), (75.0, 45.0), (45.0, 50.0)][i % 3];
+                let srgb = Srgb::from_color(Lch::new(lightness, chroma, hue)).clamp();
+                let linear = srgb.into_linear();
+                [linear.red, linear.green, linear.blue, 1.0]
+            })
+            .collect(),
+        PaletteMode::Deuteranopia | PaletteMode::Protanopia => COLOR_BLIND_SAFE.iter().map(|&rgb| from_hex(rgb)).collect(),
+        PaletteMode::HighContrast => HIGH_CONTRAST.iter().map(|&rgb| from_hex(rgb)).collect(),
+    }
+}
+
+fn from_hex(rgb: u32) -> [f32; 4] {
+    let linear = Srgb::from(rgb).into_format::<f32>().into_linear();
+    [linear.red, linear.green, linear.blue, 1.0]
+}
+
+/// Smallest CIEDE2000 difference two players' colors should have in `mode`
+pub fn threshold(mode: PaletteMode) -> f32 {
+    match mode {
+        PaletteMode::HighContrast => 30.0,
+        _ => 15.0,
+    }
+}
+
+/// How `color` looks with the color vision `mode` is made for
+fn simulate(mode: PaletteMode, color: &[f32; 4]) -> LinSrgb {
+    let matrix = match mode {
+        PaletteMode::Deuteranopia => DEUTERANOPIA,
+        PaletteMode::Protanopia => PROTANOPIA,
+        PaletteMode::Default | PaletteMode::HighContrast => return LinSrgb::new(color[0], color[1], color[2]),
+    };
+    let [red, green, blue] = matrix.map(|row| row[0] * color[0] + row[1] * color[1] + row[2] * color[2]);
+    LinSrgb::new(red, green, blue).clamp()
+}
+
+/// CIEDE2000 difference between two linear RGBA colors, as seen with the color vision `mode` is made for
+pub fn color_distance(mode: PaletteMode, a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    Lab::from_color(simulate(mode, a)).difference(Lab::from_color(simulate(mode, b)))
+}
+
+/// Colors of the players the server holds, released when a player is cleaned up so they can be picked again
+#[derive(Debug, Resource, Default)]
+pub struct SelectedColors {
+    pub colors: HashMap<ClientId, [f32; 4]>,
+    /// Palettes players asked for in their handshake
+    pub preferences: HashMap<ClientId, PaletteMode>,
+}
+
+impl SelectedColors {
+    pub fn release(&mut self, id: ClientId) -> Option<[f32; 4]> {
+        self.preferences.remove(&id);
+        self.colors.remove(&id)
+    }
+
+    /// The server's palette, then every palette a player asked for, each once and in a fixed order so the same
+    /// players always get the same candidates
+    pub fn modes(&self, mode: PaletteMode) -> Vec<PaletteMode> {
+        let mut modes = vec![mode];
+        for preferred in [
+            PaletteMode::Default,
+            PaletteMode::Deuteranopia,
+            PaletteMode::Protanopia,
+            PaletteMode::HighContrast,
+        ] {
+            if preferred != mode && self.preferences.values().any(|&asked| asked == preferred) {
+                modes.push(preferred);
+            }
+        }
+        modes
+    }
+}
+
+/// How far `candidate` is from every other player's color, relative to the threshold of the color vision it is
+/// seen with, at least 1 when every player can tell it apart
+fn score(selected: &SelectedColors, id: ClientId, modes: &[PaletteMode], candidate: &[f32; 4]) -> f32 {
+    selected
+        .colors
+        .iter()
+        .filter(|(other, _)| **other != id)
+        .flat_map(|(_, color)| {
+            modes
+                .iter()
+                .map(move |&mode| color_distance(mode, color, candidate) / threshold(mode))
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// Whether every player can tell `color` apart from the colors of the players other than `id`
+pub fn is_distinct(selected: &SelectedColors, id: ClientId, color: &[f32; 4], mode: PaletteMode) -> bool {
+    score(selected, id, &selected.modes(mode), color) >= 1.0
+}
+
+/// Pick a color for player `id` that every player can tell apart from the others, the closest to that if none is left.
+///
+/// Candidates come from `mode` first, then from the palettes players asked for.
+pub fn pick_new_player_color(selected: &mut SelectedColors, id: ClientId, mode: PaletteMode) -> [f32; 4] {
+    let modes = selected.modes(mode);
+    let mut best_candidate = [0.0; 4];
+    let mut best_score = f32::NEG_INFINITY;
+    for candidate in modes.iter().flat_map(|&mode| candidates(mode)) {
+        let score = score(selected, id, &modes, &candidate);
+        if score >= 1.0 {
+            best_candidate = candidate;
+            break;
+        }
+        if score > best_score {
+            best_score = score;
+            best_candidate = candidate;
+        }
+    }
+    selected.colors.insert(id, best_candidate);
+    best_candidate
+}
@@ -0,0 +1,349 @@
+//! Layered server configuration: built-in defaults, then a TOML file, then environment variables, then flags.
+//!
+//! Environment variables and flags are parsed by the same [`Overrides`], so a value that does not parse
+//! refuses to start the server instead of silently falling back to its default.
+
+use crate::ServerSettings;
+use bevy::prelude::*;
+use clap::Parser;
+use protocol::PaletteMode;
+use protocol::auth::{PRIVATE_KEY_ENV, PrivateKey};
+use protocol::netsim::NetworkConditions;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::{fs, io};
+
+/// Most clients the netcode server accepts, it panics when created for more
+pub const NETCODE_MAX_CLIENTS: u32 = 1024;
+
+/// How often the config file is checked for changes
+pub const CONFIG_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Settings given by one layer, `None` where the layer leaves the value to the layers below
+#[derive(Debug, Clone, Default, Parser, Deserialize)]
+#[command(about = "Multiplayer game server", version = protocol::PROTOCOL_VERSION)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    /// TOML file with any of the settings below, gameplay settings are reloaded when it changes
+    #[arg(long, env = "SERVER_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// UDP port clients connect to
+    #[arg(long, env = "SERVER_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "MAX_CLIENTS")]
+    pub max_clients: Option<u32>,
+    /// Reloadable
+    #[arg(long, env = "PLAYER_MOVE_SPEED")]
+    pub player_move_speed: Option<f32>,
+    /// Seconds a disconnected player is kept for its client to come back, reloadable
+    #[arg(long, env = "CLIENT_DISCONNECT_GRACE_PERIOD")]
+    pub client_disconnect_grace_period: Option<f64>,
+    /// Undecodable messages a client may send in a minute before it is disconnected, reloadable
+    #[arg(long, env = "MAX_DECODE_ERRORS")]
+    pub max_decode_errors: Option<u32>,
+    /// Simulation ticks per second
+    #[arg(long, env = "TICK_RATE")]
+    pub tick_rate: Option<f64>,
+    /// Comma separated addresses clients are told to connect to
+    #[arg(long, env = "SERVER_PUBLIC_ADDRESSES", value_delimiter = ',')]
+    pub public_addresses: Option<Vec<SocketAddr>>,
+    /// Clients only receive the players within this distance of their own, reloadable
+    #[arg(long, env = "INTEREST_RADIUS")]
+    pub interest_radius: Option<f32>,
+    /// TCP port serving /metrics, /healthz and /readyz
+    #[arg(long, env = "METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+    #[arg(long, env = "TICK_WATCHDOG_TIMEOUT")]
+    pub tick_watchdog_timeout: Option<f64>,
+    #[arg(long, env = "DRAIN_PERIOD")]
+    pub drain_period: Option<f64>,
+    /// `file:<dir>` or `redis://<host>:<port>`, empty to keep player state in memory only
+    #[arg(long, env = "STATE_STORE")]
+    pub state_store: Option<String>,
+    #[arg(long, env = "STATE_SAVE_INTERVAL")]
+    pub state_save_interval: Option<f64>,
+    #[arg(long, env = "SESSION_LEASE")]
+    pub session_lease: Option<f64>,
+    /// Unique among the replicas sharing a state store, e.g. the pod name, random when unset
+    #[arg(long, env = "REPLICA_ID")]
+    #[serde(skip)]
+    pub replica_id: Option<String>,
+    /// Server directory to announce this server to, empty for none
+    #[arg(long, env = "DIRECTORY_ADDR")]
+    pub directory_addr: Option<String>,
+    /// default, deuteranopia, protanopia or high-contrast, reloadable
+    #[arg(long, env = "PALETTE_MODE")]
+    pub palette_mode: Option<PaletteMode>,
+}
+
+impl Overrides {
+    /// Replace the settings this layer gives, public addresses are left to [`load`]
+    fn apply(self, settings: &mut ServerSettings) {
+        if let Some(value) = self.port {
+            settings.port = value;
+        }
+        if let Some(value) = self.max_clients {
+            settings.max_clients = value;
+        }
+        if let Some(value) = self.player_move_speed {
+            settings.player_move_speed = value;
+        }
+        if let Some(value) = self.client_disconnect_grace_period {
+            settings.client_disconnect_grace_period = value;
+        }
+        if let Some(value) = self.max_decode_errors {
+            settings.max_decode_errors = value;
+        }
+        if let Some(value) = self.tick_rate {
+            settings.tick_rate = value;
+        }
+        if let Some(value) = self.interest_radius {
+            settings.interest_radius = value;
+        }
+        if let Some(value) = self.metrics_port {
+            settings.metrics_port = value;
+        }
+        if let Some(value) = self.tick_watchdog_timeout {
+            settings.tick_watchdog_timeout = value;
+        }
+        if let Some(value) = self.drain_period {
+            settings.drain_period = value;
+        }
+        if let Some(value) = self.state_store {
+            settings.state_store = Some(value).filter(|s| !s.is_empty());
+        }
+        if let Some(value) = self.state_save_interval {
+            settings.state_save_interval = value;
+        }
+        if let Some(value) = self.session_lease {
+            settings.session_lease = value;
+        }
+        if let Some(value) = self.replica_id.filter(|s| !s.is_empty()) {
+            settings.replica_id = value;
+        }
+        if let Some(value) = self.directory_addr {
+            settings.directory_addr = Some(value).filter(|s| !s.is_empty());
+        }
+        if let Some(value) = self.palette_mode {
+            settings.palette_mode = value;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, source: io::Error },
+    Parse { path: PathBuf, source: toml::de::Error },
+    Invalid { setting: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => write!(f, "failed to read {}: {}", path.display(), source),
+            ConfigError::Parse { path, source } => write!(f, "failed to parse {}: {}", path.display(), source),
+            ConfigError::Invalid { setting, reason } => write!(f, "invalid {}: {}", setting, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Read and parse the config file
+fn read_file(path: &PathBuf) -> Result<Overrides, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.clone(),
+        source,
+    })?;
+    toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+        path: path.clone(),
+        source,
+    })
+}
+
+/// Build the settings from every layer, `cli` holds both the flags and the environment variables
+pub fn load(cli: &Overrides) -> Result<ServerSettings, ConfigError> {
+    let mut settings = ServerSettings::default();
+    let mut public_addresses = None;
+    if let Some(path) = &cli.config {
+        let file = read_file(path)?;
+        public_addresses = file.public_addresses.clone();
+        file.apply(&mut settings);
+    }
+    public_addresses = cli.public_addresses.clone().or(public_addresses);
+    cli.clone().apply(&mut settings);
+    // Without any, clients are expected on this machine at the configured port
+    settings.public_addresses = public_addresses.unwrap_or_else(|| vec![SocketAddr::from(([127, 0, 0, 1], settings.port))]);
+    // Secrets and test tooling stay out of files and flags
+    settings.private_key = match env::var(PRIVATE_KEY_ENV) {
+        Ok(hex) => Some(PrivateKey::from_hex(&hex).ok_or_else(|| invalid(PRIVATE_KEY_ENV, "must be 64 hex characters"))?),
+        Err(_) => None,
+    };
+    settings.network_conditions = NetworkConditions::from_env();
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn invalid(setting: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        setting,
+        reason: reason.into(),
+    }
+}
+
+/// Refuse a value that is NaN, infinite or below zero, each with its own reason
+fn not_negative(setting: &'static str, value: f64) -> Result<(), ConfigError> {
+    if !value.is_finite() {
+        return Err(invalid(setting, format!("{} is not a finite number", value)));
+    }
+    if value < 0.0 {
+        return Err(invalid(setting, format!("{} is negative", value)));
+    }
+    Ok(())
+}
+
+impl ServerSettings {
+    /// Refuse settings the server cannot run with
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_clients == 0 {
+            return Err(invalid("max_clients", "must be at least 1"));
+        }
+        if self.max_clients > NETCODE_MAX_CLIENTS {
+            return Err(invalid(
+                "max_clients",
+                format!("{} is more than the {} netcode supports", self.max_clients, NETCODE_MAX_CLIENTS),
+            ));
+        }
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0) {
+            return Err(invalid("tick_rate", format!("{} is not a positive number", self.tick_rate)));
+        }
+        not_negative("player_move_speed", self.player_move_speed.into())?;
+        if !(self.interest_radius.is_finite() && self.interest_radius > 0.0) {
+            return Err(invalid(
+                "interest_radius",
+                format!("{} is not a positive number", self.interest_radius),
+            ));
+        }
+        not_negative("client_disconnect_grace_period", self.client_disconnect_grace_period)?;
+        not_negative("drain_period", self.drain_period)?;
+        for (setting, seconds) in [
+            ("tick_watchdog_timeout", self.tick_watchdog_timeout),
+            ("state_save_interval", self.state_save_interval),
+            ("session_lease", self.session_lease),
+        ] {
+            if !(seconds.is_finite() && seconds > 0.0) {
+                return Err(invalid(setting, format!("{} is not a positive number", seconds)));
+            }
+        }
+        if self.public_addresses.is_empty() {
+            return Err(invalid("public_addresses", "at least one address is needed"));
+        }
+        // Otherwise other replicas take sessions over from a live owner between two renewals
+        if self.session_lease <= self.state_save_interval {
+            return Err(invalid(
+                "session_lease",
+                format!(
+                    "{} must be longer than state_save_interval {}",
+                    self.session_lease, self.state_save_interval
+                ),
+            ));
+        }
+        if let Some(url) = &self.state_store
+            && !url.starts_with("file:")
+            && !url.starts_with("redis://")
+        {
+            return Err(invalid(
+                "state_store",
+                format!("{} is neither file:<dir> nor redis://<host>:<port>", url),
+            ));
+        }
+        // Replicas sharing a store redirect clients to each other, which needs an address that reaches this one
+        if let Some(url) = &self.state_store
+            && url.starts_with("redis://")
+            && (self.public_address().ip().is_loopback() || self.public_address().ip().is_unspecified())
+        {
+            return Err(invalid(
+                "public_addresses",
+                format!(
+                    "{} cannot be reached by other replicas sharing {}, set an address that reaches this replica",
+                    self.public_address(),
+                    url
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Take the settings that can change while running from `reloaded`, returns a description of each change
+    pub fn apply_reloadable(&mut self, reloaded: &ServerSettings) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut note = |setting: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{} {} -> {}", setting, old, new));
+            }
+        };
+        note(
+            "player_move_speed",
+            self.player_move_speed.to_string(),
+            reloaded.player_move_speed.to_string(),
+        );
+        note(
+            "client_disconnect_grace_period",
+            self.client_disconnect_grace_period.to_string(),
+            reloaded.client_disconnect_grace_period.to_string(),
+        );
+        note(
+            "max_decode_errors",
+            self.max_decode_errors.to_string(),
+            reloaded.max_decode_errors.to_string(),
+        );
+        note(
+            "interest_radius",
+            self.interest_radius.to_string(),
+            reloaded.interest_radius.to_string(),
+        );
+        note(
+            "palette_mode",
+            format!("{:?}", self.palette_mode),
+            format!("{:?}", reloaded.palette_mode),
+        );
+        self.player_move_speed = reloaded.player_move_speed;
+        self.client_disconnect_grace_period = reloaded.client_disconnect_grace_period;
+        self.max_decode_errors = reloaded.max_decode_errors;
+        self.interest_radius = reloaded.interest_radius;
+        self.palette_mode = reloaded.palette_mode;
+        changes
+    }
+}
+
+/// The config file being watched, with the layers above it so a reload keeps their precedence
+#[derive(Debug, Resource)]
+pub struct ConfigWatch {
+    cli: Overrides,
+    contents: String,
+}
+
+impl ConfigWatch {
+    /// Watch the config file the server was started with, `None` without one
+    pub fn new(cli: Overrides) -> Option<Self> {
+        let path = cli.config.as_ref()?;
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        Some(Self { cli, contents })
+    }
+
+    /// Settings from the changed file, `None` if it did not change
+    pub fn reload(&mut self) -> Option<Result<ServerSettings, ConfigError>> {
+        let path = self.cli.config.as_ref()?;
+        // Unreadable files are reported once they change, an editor may be replacing the file right now
+        let contents = fs::read_to_string(path).ok()?;
+        if contents == self.contents {
+            return None;
+        }
+        self.contents = contents;
+        Some(load(&self.cli))
+    }
+}
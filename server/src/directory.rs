@@ -0,0 +1,51 @@
+//! Registration with the server directory, so clients can be sent to the least loaded server.
+
+use crate::health::SharedHealth;
+use crate::metrics::SharedMetrics;
+use bevy::prelude::*;
+use protocol::ProtocolVersion;
+use protocol::directory::{HEARTBEAT_INTERVAL, ServerStatus, send_heartbeat};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+/// How often the announce thread checks whether the app has published its first snapshots
+const PUBLISH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Announce this server to the directory every heartbeat interval, from its own thread.
+/// Player counts and readiness come from what the app last published for `/metrics` and `/readyz`,
+/// so nothing is announced before the first frame published them.
+pub fn announce(directory_addr: String, address: SocketAddr, metrics: SharedMetrics, health: SharedHealth) {
+    info!("Announcing {} to the directory at {}", address, directory_addr);
+    thread::spawn(move || {
+        while !(metrics.0.lock().unwrap().published && health.0.lock().unwrap().published) {
+            thread::sleep(PUBLISH_POLL_INTERVAL);
+        }
+        let mut reachable = true;
+        loop {
+            let status = {
+                let metrics = metrics.0.lock().unwrap();
+                ServerStatus {
+                    address,
+                    players: metrics.connected_clients as u32,
+                    capacity: metrics.max_clients,
+                    version: ProtocolVersion::current(),
+                    accepting: health.0.lock().unwrap().ready().is_ok(),
+                }
+            };
+            // Only log changes, a directory that is down would otherwise flood the log
+            match send_heartbeat(directory_addr.as_str(), &status) {
+                Ok(()) if !reachable => {
+                    info!("Directory at {} is reachable again", directory_addr);
+                    reachable = true;
+                }
+                Err(e) if reachable => {
+                    warn!("Failed to send a heartbeat to the directory at {}: {}", directory_addr, e);
+                    reachable = false;
+                }
+                _ => {}
+            }
+            thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
+}
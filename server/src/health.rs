@@ -0,0 +1,57 @@
+//! Liveness and readiness of the server, as reported on `/healthz` and `/readyz`.
+
+use bevy::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// State the probes are answered from, refreshed every frame by `publish_health_system`
+#[derive(Debug, Clone)]
+pub struct HealthSnapshot {
+    /// When the last simulation tick finished
+    pub last_tick: Instant,
+    /// The schedule counts as stalled when no tick finished for this long
+    pub watchdog_timeout: Duration,
+    pub at_capacity: bool,
+    pub draining: bool,
+    /// False until the app publishes its first snapshot
+    pub published: bool,
+}
+
+impl Default for HealthSnapshot {
+    fn default() -> Self {
+        Self {
+            last_tick: Instant::now(),
+            watchdog_timeout: Duration::from_secs(5),
+            at_capacity: false,
+            draining: false,
+            published: false,
+        }
+    }
+}
+
+impl HealthSnapshot {
+    /// `Err` with the reason when the tick watchdog has fired
+    pub fn live(&self) -> Result<(), String> {
+        let since_tick = self.last_tick.elapsed();
+        if since_tick > self.watchdog_timeout {
+            return Err(format!("no tick for {:.1}s", since_tick.as_secs_f64()));
+        }
+        Ok(())
+    }
+
+    /// `Err` with the reason when new players should not be sent here
+    pub fn ready(&self) -> Result<(), String> {
+        self.live()?;
+        if self.draining {
+            return Err("draining".to_string());
+        }
+        if self.at_capacity {
+            return Err("at max clients".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Latest health, shared between the Bevy app and the HTTP thread
+#[derive(Debug, Clone, Default, Resource)]
+pub struct SharedHealth(pub Arc<Mutex<HealthSnapshot>>);
@@ -0,0 +1,81 @@
+//! Minimal HTTP listener for the operational endpoints, each connection served from its own thread.
+
+use crate::health::SharedHealth;
+use crate::metrics::SharedMetrics;
+use bevy::prelude::*;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long a scraper gets to send its request line, well below the 1s Kubernetes probe timeout
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Connections answered at once, further ones are closed right away until one finishes
+const MAX_CONNECTIONS: usize = 16;
+
+/// Bind the HTTP port and answer requests in the background, returns the bound address
+pub fn serve(port: u16, metrics: SharedMetrics, health: SharedHealth) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let addr = listener.local_addr()?;
+    info!("HTTP endpoints listening on port: {}", addr.port());
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("HTTP connection failed: {}", e);
+                    continue;
+                }
+            };
+            // A slow client only holds up its own thread, never the probes
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warn!("Closing HTTP connection, {} are open already", MAX_CONNECTIONS);
+                continue;
+            }
+            let (metrics, health, connections) = (metrics.clone(), health.clone(), connections.clone());
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &metrics, &health) {
+                    warn!("HTTP request failed: {}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Answer a single request and close the connection
+fn handle(mut stream: TcpStream, metrics: &SharedMetrics, health: &SharedHealth) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line)? == 0 {
+        // Closed without a request, as TCP health checks do
+        return Ok(());
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+
+    let probe = |result: Result<(), String>| match result {
+        Ok(()) => ("200 OK", "text/plain", "ok\n".to_string()),
+        Err(reason) => ("503 Service Unavailable", "text/plain", format!("{}\n", reason)),
+    };
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.0.lock().unwrap().render()),
+        "/healthz" => probe(health.0.lock().unwrap().live()),
+        "/readyz" => probe(health.0.lock().unwrap().ready()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
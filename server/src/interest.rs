@@ -0,0 +1,62 @@
+//! Area of interest filtering, each client only hears about the players near its own.
+
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+use std::collections::{HashMap, HashSet};
+
+/// Players bucketed into square cells on the x/z plane, cells are as wide as the interest radius
+/// so a lookup only has to visit the cell of the center and its eight neighbours.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(ClientId, Vec3)>>,
+}
+
+impl SpatialGrid {
+    pub fn new(interest_radius: f32) -> Self {
+        Self {
+            cell_size: interest_radius,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, translation: Vec3) -> (i32, i32) {
+        (
+            (translation.x / self.cell_size).floor() as i32,
+            (translation.z / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, id: ClientId, translation: Vec3) {
+        let cell = self.cell(translation);
+        self.cells.entry(cell).or_default().push((id, translation));
+    }
+
+    /// Players within the interest radius of `center`, measured on the x/z plane
+    pub fn nearby(&self, center: Vec3) -> impl Iterator<Item = (ClientId, Vec3)> + '_ {
+        let (x, z) = self.cell(center);
+        let radius_squared = self.cell_size * self.cell_size;
+        (x - 1..=x + 1)
+            .flat_map(move |x| (z - 1..=z + 1).map(move |z| (x, z)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(move |(_, translation)| center.xz().distance_squared(translation.xz()) <= radius_squared)
+    }
+}
+
+/// Players each connected client currently knows about
+#[derive(Debug, Resource, Default)]
+pub struct ClientInterests {
+    pub clients: HashMap<ClientId, HashSet<ClientId>>,
+}
+
+impl ClientInterests {
+    /// Replace the relevant players of a client, returns the players that entered and left its area of interest
+    pub fn update(&mut self, client_id: ClientId, relevant: HashSet<ClientId>) -> (Vec<ClientId>, Vec<ClientId>) {
+        let previous = self.clients.insert(client_id, relevant).unwrap_or_default();
+        let current = &self.clients[&client_id];
+        let entered = current.difference(&previous).copied().collect();
+        let left = previous.difference(current).copied().collect();
+        (entered, left)
+    }
+}
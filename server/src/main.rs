@@ -1,123 +1,152 @@
+mod colors;
+mod config;
+mod directory;
+mod health;
+mod http;
+mod interest;
+mod metrics;
+mod profile;
+mod store;
+#[cfg(test)]
+mod tests;
+
 use bevy::input::InputPlugin;
 use bevy::log::LogPlugin;
 use bevy::time::TimePlugin;
+use bevy::time::common_conditions::on_timer;
 use bevy::{app::ScheduleRunnerPlugin, prelude::*};
 use bevy_renet::RenetServerPlugin;
-use bevy_renet::netcode::{NetcodeServerPlugin, NetcodeServerTransport, NetcodeTransportError, ServerAuthentication, ServerConfig};
+use bevy_renet::netcode::{
+    NETCODE_USER_DATA_BYTES, NetcodeError, NetcodeServerPlugin, NetcodeServerTransport, NetcodeTransportError, ServerAuthentication,
+    ServerConfig, generate_random_bytes,
+};
 use bevy_renet::renet::{ClientId, ConnectionConfig, DefaultChannel, RenetServer, ServerEvent};
+use clap::Parser;
+use colors::{SelectedColors, pick_new_player_color};
+use config::{CONFIG_CHECK_INTERVAL, ConfigWatch, Overrides};
 use core::time::Duration;
-use serde::{Deserialize, Serialize};
-use std::env;
-use std::time::SystemTime;
-use std::{collections::HashMap, net::UdpSocket};
-
-const PROTOCOL_ID: u64 = 7;
-
-#[derive(Debug, Default, Serialize, Deserialize, Component, Resource)]
-struct PlayerInput {
-    up: bool,
-    down: bool,
-    left: bool,
-    right: bool,
-}
+use health::SharedHealth;
+use interest::{ClientInterests, SpatialGrid};
+use metrics::{ChannelTraffic, ClientNetwork, MetricsSnapshot, SharedMetrics, TickTiming};
+use protocol::auth::{PRIVATE_KEY_ENV, PrivateKey};
+use protocol::movement::{SimulationSettings, move_player};
+use protocol::netsim::{self, NetworkConditions};
+use protocol::session::SessionToken;
+use protocol::snapshot::{SnapshotAck, SnapshotSender, WorldState, quantize};
+use protocol::{ClientMessages, Handshake, InputCommand, Lobby, PROTOCOL_ID, PaletteMode, PlayerInput, RejectReason, ServerMessages};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use store::{Batch, Lookup, PlayerState, PlayerStore, SessionOwner};
 
 #[derive(Debug, Component)]
 struct Player {
     id: ClientId,
 }
 
-#[derive(Debug, Serialize, Deserialize, Component, Clone)]
+#[derive(Debug, Component, Clone)]
 struct PlayerColor([f32; 4]);
 
-#[derive(Debug, Component)]
-struct Disconnected {
-    disconnect_time: f64,
-}
+/// Name a player picked with `ClientMessages::SetProfile`
+#[derive(Debug, Component, Clone)]
+struct PlayerName(String);
 
-#[derive(Debug, Default, Resource)]
-struct Lobby {
-    players: HashMap<ClientId, Entity>,
+/// Inputs received from a client that have not been simulated yet, one is applied per tick
+#[derive(Debug, Component, Default)]
+struct InputBuffer {
+    pending: VecDeque<InputCommand>,
+    last_received_sequence: Option<u32>,
+    last_applied_sequence: u32,
 }
 
+/// Most inputs kept per client, older ones are dropped when a client sends faster than the tick rate
+const MAX_BUFFERED_INPUTS: usize = 8;
+
+/// Number of simulation ticks run so far
+#[derive(Debug, Resource, Default)]
+struct ServerTick(u32);
+
+/// Snapshot history of each connected client, used to delta encode against what it acknowledged
 #[derive(Debug, Resource, Default)]
-struct SelectedColors {
-    colors: Vec<[f32; 4]>,
+struct SnapshotSenders {
+    clients: HashMap<ClientId, SnapshotSender>,
 }
 
-/// Get a player color based on index and max_clients
-fn get_player_color(index: usize) -> [f32; 4] {
-    use palette::{FromColor, Hsl, LinSrgba, Srgb, WithAlpha};
+/// Secret the client presents to reclaim this player after reconnecting
+#[derive(Debug, Component)]
+struct Session(SessionToken);
 
-    // Golden ratio conjugate for hue spacing
-    const GOLDEN_RATIO_CONJUGATE: f32 = 0.618_034;
+/// Player entities by their current session token
+#[derive(Debug, Resource, Default)]
+struct Sessions {
+    players: HashMap<SessionToken, Entity>,
+}
 
-    // Base hue (theme starting point, can be adjusted)
-    let base_hue = 220.0; // Starting from a bluish tone instead of middle greens
+/// Clients waiting for the store to say where their session is held and what state to restore, with their handshake
+#[derive(Debug, Resource, Default)]
+struct PendingJoins {
+    clients: HashMap<ClientId, Handshake>,
+}
 
-    // Use a prime multiplier to further disrupt clustering
-    let raw_hue = (base_hue + (index as f32 * GOLDEN_RATIO_CONJUGATE * 137.5)) % 360.0;
+/// Longest the server waits on shutdown for the store to save every player
+const STORE_FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
 
-    // Normalize to avoid excessive greens (skip 80-160 degrees where greens dominate)
-    let hue = if (80.0..160.0).contains(&raw_hue) {
-        (160.0 + (raw_hue - 80.0)) % 360.0 // Shift to a less green range
-    } else {
-        raw_hue
-    };
+#[derive(Debug, Component)]
+struct Disconnected {
+    disconnect_time: f64,
+}
 
-    // Introduce slight variation in saturation and lightness
-    let saturation_variation = ((index * 37) % 10) as f32 * 0.015; // Small variation
-    let lightness_variation = ((index * 53) % 10) as f32 * 0.015; // Small variation
+/// Clients that failed the handshake or connected during shutdown, with the time they were rejected.
+/// They are disconnected a moment later so the rejection message has a chance to be delivered.
+#[derive(Debug, Resource, Default)]
+struct RejectedClients {
+    clients: HashMap<ClientId, f64>,
+}
 
-    let saturation = 0.65 + saturation_variation; // Keeps within range (0.0 to 1.0)
-    let lightness = 0.45 + lightness_variation; // Adjust for better contrast
+/// Seconds to wait between sending `ServerMessages::ConnectionRejected` and disconnecting the client
+const REJECT_DISCONNECT_DELAY: f64 = 1.0;
 
-    // Convert HSL to linear RGB
-    let hsl_color = Hsl::new(hue, saturation, lightness);
-    let srgb_color: Srgb<f32> = Srgb::from_color(hsl_color);
-    let linear_color: LinSrgba<f32> = srgb_color.into_linear().with_alpha(1.0);
+/// Delay suggested to clients before they try to reconnect after a shutdown
+const SHUTDOWN_RECONNECT_HINT: Duration = Duration::from_secs(5);
 
-    // Return as [f32; 4]
-    [linear_color.red, linear_color.green, linear_color.blue, linear_color.alpha]
-}
+/// Seconds to keep going after a fatal transport error, enough to save every player and try to warn the clients
+const FATAL_ERROR_SHUTDOWN_DELAY: f64 = 0.5;
 
-/// Compute color distance between two colors to avoid similar colors
-fn color_distance(a: &[f32; 4], b: &[f32; 4]) -> f32 {
-    let dr = a[0] - b[0];
-    let dg = a[1] - b[1];
-    let db = a[2] - b[2];
-    (dr * dr + dg * dg + db * db).sqrt()
+/// Inserted once the server has decided to shut down
+#[derive(Debug, Resource)]
+struct ShuttingDown {
+    started: f64,
+    /// Seconds to keep serving connected clients before disconnecting them
+    delay: f64,
+    exit: AppExit,
 }
 
-/// Pick a unique player color
-fn pick_new_player_color(selected: &mut SelectedColors) -> [f32; 4] {
-    let base = selected.colors.len();
-    let threshold = 0.3;
-    let mut best_candidate = [0.0; 4];
-    let mut best_distance = 0.0;
-    for i in 0..20 {
-        let candidate = get_player_color(base + i);
-        let min_distance = selected
-            .colors
-            .iter()
-            .map(|c| color_distance(c, &candidate))
-            .fold(f32::INFINITY, f32::min);
-        if min_distance >= threshold {
-            selected.colors.push(candidate);
-            return candidate;
-        }
-        if min_distance > best_distance {
-            best_distance = min_distance;
-            best_candidate = candidate;
-        }
-    }
-    selected.colors.push(best_candidate);
-    best_candidate
+/// Set from the signal handler when the process is asked to terminate
+#[derive(Debug, Resource, Clone, Default)]
+struct ShutdownSignal(Arc<AtomicBool>);
+
+/// How the server reacts to a transport error
+#[derive(Debug, PartialEq, Eq)]
+enum TransportErrorAction {
+    /// Caused by some peer the transport could not tell, netcode times out the clients that really went away
+    Count,
+    /// The socket itself is unusable, exit so the server is restarted
+    Exit,
 }
 
-#[derive(Debug, Serialize, Deserialize, Component)]
-enum ServerMessages {
-    PlayerConnected { id: ClientId, color: [f32; 4] },
-    PlayerDisconnected { id: ClientId },
+/// Transport errors that were logged and otherwise ignored since startup
+#[derive(Debug, Resource, Default)]
+struct TransportErrors(u64);
+
+/// Seconds over which undecodable messages are counted, so occasional errors on a long connection add up to nothing
+const DECODE_ERROR_WINDOW: f64 = 60.0;
+
+/// Undecodable messages received from each connected client in its current window, and when that window started
+#[derive(Debug, Resource, Default)]
+struct DecodeErrors {
+    counts: HashMap<ClientId, (u32, f64)>,
 }
 
 #[derive(Resource, Clone, Debug)]
@@ -126,24 +155,109 @@ struct ServerSettings {
     max_clients: u32,
     player_move_speed: f32,
     client_disconnect_grace_period: f64,
+    /// Undecodable messages a client may send within `DECODE_ERROR_WINDOW` before it is disconnected
+    max_decode_errors: u32,
+    /// Simulation ticks per second
+    tick_rate: f64,
+    /// Addresses clients are told to connect to, secure connect tokens must list one of them
+    public_addresses: Vec<SocketAddr>,
+    /// Key shared with the token service, clients connect without authentication when unset
+    private_key: Option<PrivateKey>,
+    /// Clients only receive the players within this distance of their own
+    interest_radius: f32,
+    /// TCP port of the HTTP endpoint serving `/metrics`, `/healthz` and `/readyz`
+    metrics_port: u16,
+    /// Seconds without a finished tick before the server reports itself stalled
+    tick_watchdog_timeout: f64,
+    /// Seconds connected clients keep playing after SIGTERM before they are disconnected
+    drain_period: f64,
+    /// Where player state is persisted, `file:<dir>` or `redis://<host>:<port>`, kept in memory only when unset
+    state_store: Option<String>,
+    /// Seconds between saves of every connected player's state, session leases are renewed as often
+    state_save_interval: f64,
+    /// Seconds other replicas wait for a silent owner before taking over its sessions, longer than the save interval
+    session_lease: f64,
+    /// Identifies this replica as the owner of its sessions, unique among the replicas sharing a store
+    replica_id: String,
+    /// Server directory to announce this server to, clients find it there when set
+    directory_addr: Option<String>,
+    /// Latency, jitter, loss, reordering and duplication to simulate on the UDP port, none when unset
+    network_conditions: Option<NetworkConditions>,
+    /// Palette new players' colors are picked from
+    palette_mode: PaletteMode,
 }
 
 impl Default for ServerSettings {
+    /// Built-in defaults, see [`config::load`] for the settings the server actually runs with
     fn default() -> Self {
         Self {
-            port: env::var("SERVER_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(5000),
-            max_clients: env::var("MAX_CLIENTS").ok().and_then(|s| s.parse().ok()).unwrap_or(64),
-            player_move_speed: env::var("PLAYER_MOVE_SPEED").ok().and_then(|s| s.parse().ok()).unwrap_or(1.0),
-            client_disconnect_grace_period: env::var("CLIENT_DISCONNECT_GRACE_PERIOD")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(30.0),
+            port: 5000,
+            max_clients: 64,
+            player_move_speed: 1.0,
+            client_disconnect_grace_period: 30.0,
+            max_decode_errors: 10,
+            tick_rate: 60.0,
+            public_addresses: vec![SocketAddr::from(([127, 0, 0, 1], 5000))],
+            private_key: None,
+            interest_radius: 1000.0,
+            metrics_port: 9000,
+            tick_watchdog_timeout: 5.0,
+            drain_period: 10.0,
+            state_store: None,
+            state_save_interval: 10.0,
+            session_lease: 30.0,
+            replica_id: random_replica_id(),
+            directory_addr: None,
+            network_conditions: None,
+            palette_mode: PaletteMode::Default,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Address this replica is reached at, recorded as the owner of its sessions
+    fn public_address(&self) -> SocketAddr {
+        self.public_addresses[0]
+    }
+
+    /// When a session lease taken now runs out
+    fn lease_expiry(&self) -> u64 {
+        unix_time() + self.session_lease.ceil() as u64
+    }
+
+    /// The part of the settings clients need to predict movement
+    fn simulation(&self) -> SimulationSettings {
+        SimulationSettings {
+            tick_rate: self.tick_rate,
+            player_move_speed: self.player_move_speed,
         }
     }
 }
 
+/// Random replica id, for replicas not given one
+fn random_replica_id() -> String {
+    generate_random_bytes::<16>().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 /// Run bevy server
 fn main() {
+    let cli = Overrides::parse();
+    let settings = config::load(&cli).unwrap_or_else(|e| {
+        eprintln!("Refusing to start: {}", e);
+        std::process::exit(2);
+    });
+    let mut app = build_app(settings);
+    if let Some(watch) = ConfigWatch::new(cli) {
+        app.insert_resource(watch);
+    }
+    // The handler is process wide, so it is installed here rather than in build_app
+    let signal = app.world().resource::<ShutdownSignal>().clone();
+    ctrlc::set_handler(move || signal.0.store(true, Ordering::SeqCst)).expect("failed to install the signal handler");
+    app.run();
+}
+
+/// Build the server app, binding its UDP and HTTP ports
+fn build_app(mut server_settings: ServerSettings) -> App {
     let mut app = App::new();
     app.add_plugins((
         TimePlugin,
@@ -154,38 +268,133 @@ fn main() {
         },
         LogPlugin::default(),
     ));
-    info!("Starting server...");
-    let server_settings = ServerSettings::default();
+    info!("Starting server with protocol {}...", Handshake::current());
     let (renet_server, renet_transport) = new_renet_server(&server_settings);
     info!("{:?}", server_settings);
-    app.add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0)))
-        .init_resource::<Lobby>()
-        .init_resource::<SelectedColors>()
-        .add_plugins((RenetServerPlugin, NetcodeServerPlugin))
-        .insert_resource(renet_server)
-        .insert_resource(renet_transport)
-        .insert_resource(server_settings)
-        .add_systems(
-            Update,
-            (server_update_system, server_sync_players, move_players_system).run_if(resource_exists::<RenetServer>),
+    let metrics = SharedMetrics::default();
+    let health = SharedHealth::default();
+    let http_address = http::serve(server_settings.metrics_port, metrics.clone(), health.clone()).expect("failed to bind the metrics port");
+    server_settings.metrics_port = http_address.port();
+    if let Some(directory_addr) = &server_settings.directory_addr {
+        directory::announce(
+            directory_addr.clone(),
+            server_settings.public_address(),
+            metrics.clone(),
+            health.clone(),
+        );
+    }
+    if let Some(url) = &server_settings.state_store {
+        info!("Persisting player state to {}", url);
+        app.insert_resource(PlayerStore::spawn(store::open(url).expect("failed to open the state store")));
+    }
+    let save_interval = Duration::from_secs_f64(server_settings.state_save_interval);
+    app.add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+        1.0 / server_settings.tick_rate,
+    )))
+    .insert_resource(Time::<Fixed>::from_hz(server_settings.tick_rate))
+    .init_resource::<ServerTick>()
+    .init_resource::<Lobby>()
+    .init_resource::<SelectedColors>()
+    .init_resource::<RejectedClients>()
+    .init_resource::<DecodeErrors>()
+    .init_resource::<Sessions>()
+    .init_resource::<PendingJoins>()
+    .init_resource::<SnapshotSenders>()
+    .init_resource::<ClientInterests>()
+    .init_resource::<ChannelTraffic>()
+    .init_resource::<TickTiming>()
+    .init_resource::<TransportErrors>()
+    .init_resource::<ShutdownSignal>()
+    .insert_resource(metrics)
+    .insert_resource(health)
+    .add_plugins((RenetServerPlugin, NetcodeServerPlugin))
+    .insert_resource(renet_server)
+    .insert_resource(renet_transport)
+    .insert_resource(server_settings)
+    .add_systems(
+        Update,
+        (
+            server_update_system,
+            finish_joins_system.run_if(resource_exists::<PlayerStore>),
+            disconnect_rejected_system,
         )
-        .add_systems(Update, (cleanup_disconnected_system, panic_on_error_system))
-        .run();
+            .chain()
+            .run_if(resource_exists::<RenetServer>),
+    )
+    .add_systems(
+        FixedUpdate,
+        (
+            start_tick_system,
+            apply_inputs_system,
+            move_players_system,
+            server_sync_players,
+            finish_tick_system,
+        )
+            .chain()
+            .run_if(resource_exists::<RenetServer>),
+    )
+    .add_systems(Update, cleanup_disconnected_system)
+    .add_systems(
+        Update,
+        (
+            save_disconnected_players_system.after(server_update_system),
+            save_players_system.run_if(on_timer(save_interval)),
+        )
+            .run_if(resource_exists::<PlayerStore>)
+            // A lease renewed after the handover would keep other replicas from taking the player over
+            .before(shutdown_system),
+    )
+    .add_systems(
+        Update,
+        (publish_metrics_system, publish_health_system).run_if(resource_exists::<RenetServer>),
+    )
+    .add_systems(
+        Update,
+        reload_config_system
+            .run_if(resource_exists::<ConfigWatch>)
+            .run_if(on_timer(CONFIG_CHECK_INTERVAL)),
+    )
+    .add_systems(
+        Update,
+        (signal_system, transport_error_system, shutdown_system)
+            .chain()
+            .run_if(resource_exists::<RenetServer>)
+            .run_if(resource_exists::<NetcodeServerTransport>),
+    );
+    app
 }
 
 /// Create a new Renet server and Netcode transport using settings from ServerSettings.
 fn new_renet_server(settings: &ServerSettings) -> (RenetServer, NetcodeServerTransport) {
     let port = settings.port;
     info!("Server listening on port: {}", port);
-    let public_addr = format!("0.0.0.0:{}", port).parse().unwrap();
-    let socket = UdpSocket::bind(public_addr).unwrap();
+    let bind_addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
+    let socket = match settings.network_conditions {
+        Some(conditions) => {
+            warn!("Simulating network conditions {:?}", conditions);
+            netsim::bind_server_socket(bind_addr, conditions).unwrap()
+        }
+        None => UdpSocket::bind(bind_addr).unwrap(),
+    };
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let authentication = match &settings.private_key {
+        Some(private_key) => ServerAuthentication::Secure {
+            private_key: private_key.0,
+        },
+        None => {
+            warn!(
+                "{} is not set, clients can connect without a token and pick their own id",
+                PRIVATE_KEY_ENV
+            );
+            ServerAuthentication::Unsecure
+        }
+    };
     let server_config = ServerConfig {
         current_time,
         max_clients: settings.max_clients as usize,
         protocol_id: PROTOCOL_ID,
-        public_addresses: vec![public_addr],
-        authentication: ServerAuthentication::Unsecure,
+        public_addresses: settings.public_addresses.clone(),
+        authentication,
     };
 
     let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
@@ -193,78 +402,477 @@ fn new_renet_server(settings: &ServerSettings) -> (RenetServer, NetcodeServerTra
     (server, transport)
 }
 
+/// Check the handshake a client sent in its netcode user data against our own protocol version
+fn check_handshake(user_data: Option<&[u8; NETCODE_USER_DATA_BYTES]>) -> Result<Handshake, RejectReason> {
+    let server = Handshake::current();
+    let Some(client) = user_data.and_then(Handshake::from_user_data) else {
+        return Err(RejectReason::MissingHandshake);
+    };
+    if !server.version.is_compatible_with(&client.version) {
+        return Err(RejectReason::IncompatibleVersion { server, client });
+    }
+    if server.build_hash != client.build_hash {
+        warn!("Client build {} differs from server build {}", client, server);
+    }
+    Ok(client)
+}
+
+/// Tell a client why it is refused, it gets disconnected by `disconnect_rejected_system`
+fn reject_client(
+    server: &mut RenetServer,
+    traffic: &mut ChannelTraffic,
+    rejected_clients: &mut RejectedClients,
+    client_id: ClientId,
+    reason: RejectReason,
+    now: f64,
+) {
+    warn!("Rejecting player {}: {}", client_id, reason);
+    let message = bincode::serialize(&ServerMessages::ConnectionRejected { reason }).unwrap();
+    traffic.send(server, client_id, DefaultChannel::ReliableOrdered, message);
+    rejected_clients.clients.insert(client_id, now);
+}
+
 /// System to handle server events and player input
+#[allow(clippy::too_many_arguments)]
 fn server_update_system(
     mut server_events: EventReader<ServerEvent>,
     mut commands: Commands,
     mut lobby: ResMut<Lobby>,
     mut selected_colors: ResMut<SelectedColors>,
+    mut rejected_clients: ResMut<RejectedClients>,
+    mut decode_errors: ResMut<DecodeErrors>,
+    mut sessions: ResMut<Sessions>,
+    (mut snapshot_senders, mut interests): (ResMut<SnapshotSenders>, ResMut<ClientInterests>),
+    mut traffic: ResMut<ChannelTraffic>,
+    mut players: Query<(&mut Player, &mut PlayerColor, Has<Disconnected>)>,
+    mut input_buffers: Query<&mut InputBuffer>,
     mut server: ResMut<RenetServer>,
+    (transport, shutting_down): (Res<NetcodeServerTransport>, Option<Res<ShuttingDown>>),
+    (store, mut pending_joins): (Option<Res<PlayerStore>>, ResMut<PendingJoins>),
+    server_settings: Res<ServerSettings>,
     time: Res<Time>,
 ) {
     for event in server_events.read() {
         match event {
+            ServerEvent::ClientConnected { client_id } if shutting_down.is_some() => {
+                // Not a rejection, the client should come back and land on another replica
+                info!("Turning away player {} while shutting down.", client_id);
+                let message = bincode::serialize(&ServerMessages::ServerShuttingDown {
+                    reconnect_hint: Some(SHUTDOWN_RECONNECT_HINT),
+                })
+                .unwrap();
+                traffic.send(&mut server, *client_id, DefaultChannel::ReliableOrdered, message);
+                rejected_clients.clients.insert(*client_id, time.elapsed_secs_f64());
+            }
             ServerEvent::ClientConnected { client_id } => {
+                let handshake = match check_handshake(transport.user_data(*client_id).as_ref()) {
+                    Ok(handshake) => handshake,
+                    Err(reason) => {
+                        reject_client(
+                            &mut server,
+                            &mut traffic,
+                            &mut rejected_clients,
+                            *client_id,
+                            reason,
+                            time.elapsed_secs_f64(),
+                        );
+                        continue;
+                    }
+                };
                 info!("Player {} connected.", client_id);
-                if let Some(&player_entity) = lobby.players.get(client_id) {
-                    // If reconnecting, remove Disconnected marker if it exists.
-                    commands.entity(player_entity).remove::<Disconnected>();
-                    info!("Reattached client {} to existing entity.", client_id);
-                } else {
-                    // Instead of using get_player_color(index), use the new resource function.
-                    let color = pick_new_player_color(&mut selected_colors);
-                    let player_entity = commands
-                        .spawn((
-                            Transform::from_xyz(0.0, 0.5, 0.0),
-                            PlayerInput::default(),
-                            Player { id: *client_id },
-                            PlayerColor(color),
-                        ))
-                        .id();
-                    lobby.players.insert(*client_id, player_entity);
 
-                    // Broadcast connection info with the assigned color.
-                    let message = bincode::serialize(&ServerMessages::PlayerConnected { id: *client_id, color }).unwrap();
-                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                // A player is reclaimed with its session token, whether it waits out its grace period or, when the
+                // client asks to take it over, its old connection has not timed out yet
+                let reclaimed = handshake
+                    .session
+                    .and_then(|token| sessions.players.get(&token).copied())
+                    .filter(|&entity| players.contains(entity));
+                let still_playing = reclaimed.is_some_and(|entity| {
+                    players
+                        .get(entity)
+                        .is_ok_and(|(player, _, disconnected)| !disconnected && player.id != *client_id)
+                });
+                if still_playing && !handshake.take_over {
+                    info!("Refusing player {}, its session is still played by another connection.", client_id);
+                    reject_client(
+                        &mut server,
+                        &mut traffic,
+                        &mut rejected_clients,
+                        *client_id,
+                        RejectReason::ClientIdInUse,
+                        time.elapsed_secs_f64(),
+                    );
+                } else if let Some(player_entity) = reclaimed {
+                    let (mut player, player_color, disconnected) = players.get_mut(player_entity).unwrap();
+                    if !disconnected && player.id != *client_id {
+                        info!(
+                            "Dropping the stale connection of player {}, it reconnected as {}.",
+                            player.id, client_id
+                        );
+                        server.disconnect(player.id);
+                        let message = bincode::serialize(&ServerMessages::PlayerDisconnected { id: player.id }).unwrap();
+                        traffic.broadcast(&mut server, DefaultChannel::ReliableOrdered, message);
+                    }
+                    lobby.players.remove(&player.id);
+                    lobby.players.insert(*client_id, player_entity);
+                    selected_colors.release(player.id);
+                    selected_colors.colors.insert(*client_id, player_color.0);
+                    if let Some(palette) = handshake.palette {
+                        selected_colors.preferences.insert(*client_id, palette);
+                    }
+                    info!("Reattached client {} to the entity of player {}.", client_id, player.id);
+                    player.id = *client_id;
+                    commands
+                        .entity(player_entity)
+                        .remove::<Disconnected>()
+                        .insert((PlayerInput::default(), InputBuffer::default()));
+                    let color = player_color.0;
+                    welcome_player(
+                        &mut commands,
+                        &mut server,
+                        &mut traffic,
+                        &mut sessions,
+                        store.as_deref(),
+                        &server_settings,
+                        *client_id,
+                        &handshake,
+                        player_entity,
+                        color,
+                    );
+                } else if lobby.players.contains_key(client_id) {
+                    reject_client(
+                        &mut server,
+                        &mut traffic,
+                        &mut rejected_clients,
+                        *client_id,
+                        RejectReason::ClientIdInUse,
+                        time.elapsed_secs_f64(),
+                    );
+                } else if let Some(store) = &store {
+                    // The session may belong to another replica, finished by finish_joins_system once the store answers
+                    store.lookup(*client_id, handshake.session);
+                    pending_joins.clients.insert(*client_id, handshake);
+                } else {
+                    let (player_entity, color) = spawn_player(
+                        &mut commands,
+                        &mut lobby,
+                        &mut selected_colors,
+                        &server_settings,
+                        *client_id,
+                        &handshake,
+                        None,
+                    );
+                    welcome_player(
+                        &mut commands,
+                        &mut server,
+                        &mut traffic,
+                        &mut sessions,
+                        None,
+                        &server_settings,
+                        *client_id,
+                        &handshake,
+                        player_entity,
+                        color,
+                    );
                 }
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 info!("Player {} disconnected: {}", client_id, reason);
+                rejected_clients.clients.remove(client_id);
+                decode_errors.counts.remove(client_id);
+                snapshot_senders.clients.remove(client_id);
+                interests.clients.remove(client_id);
+                pending_joins.clients.remove(client_id);
                 if let Some(&player_entity) = lobby.players.get(client_id) {
                     // Mark as disconnected instead of despawning immediately.
                     commands.entity(player_entity).insert(Disconnected {
                         disconnect_time: time.elapsed_secs_f64(),
                     });
                     let message = bincode::serialize(&ServerMessages::PlayerDisconnected { id: *client_id }).unwrap();
-                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                    traffic.broadcast(&mut server, DefaultChannel::ReliableOrdered, message);
                 }
             }
         }
     }
 
     for client_id in server.clients_id() {
-        while let Some(message) = server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
-            let player_input: PlayerInput = bincode::deserialize(&message).unwrap();
-            if let Some(&player_entity) = lobby.players.get(&client_id) {
-                commands.entity(player_entity).insert(player_input);
+        while let Some(message) = traffic.receive(&mut server, client_id, DefaultChannel::ReliableOrdered) {
+            let command = match protocol::decode(&message) {
+                Ok(ClientMessages::Input { command }) => command,
+                Ok(ClientMessages::SetProfile { name, preferred_color }) => {
+                    let Some((_, mut player_color, _)) = lobby.players.get(&client_id).and_then(|&entity| players.get_mut(entity).ok())
+                    else {
+                        continue;
+                    };
+                    let profile = profile::validate_name(&name).and_then(|name| {
+                        let color = match preferred_color {
+                            Some(color) => profile::validate_color(color, &selected_colors, client_id, server_settings.palette_mode)?,
+                            None => player_color.0,
+                        };
+                        Ok((name, color))
+                    });
+                    match profile {
+                        Ok((name, color)) => {
+                            info!("Player {} is now called {}", client_id, name);
+                            player_color.0 = color;
+                            selected_colors.colors.insert(client_id, color);
+                            commands.entity(lobby.players[&client_id]).insert(PlayerName(name.clone()));
+                            let message = bincode::serialize(&ServerMessages::ProfileChanged {
+                                id: client_id,
+                                name,
+                                color,
+                            })
+                            .unwrap();
+                            traffic.broadcast(&mut server, DefaultChannel::ReliableOrdered, message);
+                        }
+                        Err(reason) => {
+                            info!("Refused the profile of player {}: {}", client_id, reason);
+                            let message = bincode::serialize(&ServerMessages::ProfileRejected { reason }).unwrap();
+                            traffic.send(&mut server, client_id, DefaultChannel::ReliableOrdered, message);
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    if record_decode_error(
+                        &mut server,
+                        &mut decode_errors,
+                        &server_settings,
+                        client_id,
+                        message.len(),
+                        &e,
+                        time.elapsed_secs_f64(),
+                    ) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let Some(mut buffer) = lobby.players.get(&client_id).and_then(|&entity| input_buffers.get_mut(entity).ok()) else {
+                continue;
+            };
+            if buffer.last_received_sequence.is_some_and(|last| command.sequence <= last) {
+                continue;
             }
+            buffer.last_received_sequence = Some(command.sequence);
+            if buffer.pending.len() >= MAX_BUFFERED_INPUTS {
+                buffer.pending.pop_front();
+            }
+            buffer.pending.push_back(command);
+        }
+
+        while let Some(message) = traffic.receive(&mut server, client_id, DefaultChannel::Unreliable) {
+            match protocol::decode::<SnapshotAck>(&message) {
+                Ok(SnapshotAck { tick }) => {
+                    if let Some(sender) = snapshot_senders.clients.get_mut(&client_id) {
+                        sender.ack(tick);
+                    }
+                }
+                Err(e) => {
+                    if record_decode_error(
+                        &mut server,
+                        &mut decode_errors,
+                        &server_settings,
+                        client_id,
+                        message.len(),
+                        &e,
+                        time.elapsed_secs_f64(),
+                    ) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Spawn a player for a client the server does not hold, where it was saved or at the start with a new color
+fn spawn_player(
+    commands: &mut Commands,
+    lobby: &mut Lobby,
+    selected_colors: &mut SelectedColors,
+    server_settings: &ServerSettings,
+    client_id: ClientId,
+    handshake: &Handshake,
+    saved: Option<PlayerState>,
+) -> (Entity, [f32; 4]) {
+    if let Some(palette) = handshake.palette {
+        selected_colors.preferences.insert(client_id, palette);
+    }
+    let (translation, color) = if let Some(saved) = saved {
+        info!("Restored player {} at {}", client_id, saved.translation);
+        selected_colors.colors.insert(client_id, saved.color);
+        (saved.translation, saved.color)
+    } else {
+        (
+            Vec3::new(0.0, 0.5, 0.0),
+            pick_new_player_color(selected_colors, client_id, server_settings.palette_mode),
+        )
+    };
+    let player_entity = commands
+        .spawn((
+            Transform::from_translation(translation),
+            PlayerInput::default(),
+            InputBuffer::default(),
+            Player { id: client_id },
+            PlayerColor(color),
+        ))
+        .id();
+    lobby.players.insert(client_id, player_entity);
+    (player_entity, color)
+}
+
+/// Hand a client that now has a player its session token and the simulation settings, and tell everyone it joined
+#[allow(clippy::too_many_arguments)]
+fn welcome_player(
+    commands: &mut Commands,
+    server: &mut RenetServer,
+    traffic: &mut ChannelTraffic,
+    sessions: &mut Sessions,
+    store: Option<&PlayerStore>,
+    server_settings: &ServerSettings,
+    client_id: ClientId,
+    handshake: &Handshake,
+    player_entity: Entity,
+    color: [f32; 4],
+) {
+    // A fresh token every connection, so a leaked token is only good until the player next joins
+    sessions.players.retain(|_, &mut entity| entity != player_entity);
+    let token = SessionToken(generate_random_bytes());
+    sessions.players.insert(token, player_entity);
+    commands.entity(player_entity).insert(Session(token));
+    if let Some(store) = store {
+        if let Some(previous) = handshake.session {
+            store.remove_owner(previous);
+        }
+        claim_session(store, server_settings, &token, client_id, server_settings.lease_expiry());
+    }
+    let message = bincode::serialize(&ServerMessages::SessionAssigned { token }).unwrap();
+    traffic.send(server, client_id, DefaultChannel::ReliableOrdered, message);
+    let message = bincode::serialize(&ServerMessages::Simulation {
+        settings: server_settings.simulation(),
+    })
+    .unwrap();
+    traffic.send(server, client_id, DefaultChannel::ReliableOrdered, message);
+
+    // Broadcast connection info with the assigned color.
+    let message = bincode::serialize(&ServerMessages::PlayerConnected { id: client_id, color }).unwrap();
+    traffic.broadcast(server, DefaultChannel::ReliableOrdered, message);
+}
+
+/// System to let in the clients the store answered for, or send them to the replica holding their session
+#[allow(clippy::too_many_arguments)]
+fn finish_joins_system(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut traffic: ResMut<ChannelTraffic>,
+    mut lobby: ResMut<Lobby>,
+    mut selected_colors: ResMut<SelectedColors>,
+    mut sessions: ResMut<Sessions>,
+    mut rejected_clients: ResMut<RejectedClients>,
+    mut pending_joins: ResMut<PendingJoins>,
+    store: Res<PlayerStore>,
+    server_settings: Res<ServerSettings>,
+    time: Res<Time>,
+) {
+    for Lookup { client_id, owner, saved } in store.lookups() {
+        // Gone while the store was looked up
+        let Some(handshake) = pending_joins.clients.remove(&client_id) else {
+            continue;
+        };
+        // A session this replica does not hold may belong to a live peer, or to one that went away
+        if let Some(owner) = owner.filter(|owner| owner.replica != server_settings.replica_id && !owner.is_expired(unix_time())) {
+            info!("Redirecting player {} to {}, which holds its session.", client_id, owner.address);
+            let message = bincode::serialize(&ServerMessages::Redirect { address: owner.address }).unwrap();
+            traffic.send(&mut server, client_id, DefaultChannel::ReliableOrdered, message);
+            rejected_clients.clients.insert(client_id, time.elapsed_secs_f64());
+            continue;
+        }
+        let (player_entity, color) = spawn_player(
+            &mut commands,
+            &mut lobby,
+            &mut selected_colors,
+            &server_settings,
+            client_id,
+            &handshake,
+            saved,
+        );
+        welcome_player(
+            &mut commands,
+            &mut server,
+            &mut traffic,
+            &mut sessions,
+            Some(&store),
+            &server_settings,
+            client_id,
+            &handshake,
+            player_entity,
+            color,
+        );
+    }
+}
+
+/// Count an undecodable message from a client, returns true if the client was disconnected for sending too many
+fn record_decode_error(
+    server: &mut RenetServer,
+    decode_errors: &mut DecodeErrors,
+    server_settings: &ServerSettings,
+    client_id: ClientId,
+    message_len: usize,
+    error: &bincode::Error,
+    now: f64,
+) -> bool {
+    let (errors, window_started) = decode_errors.counts.entry(client_id).or_insert((0, now));
+    if now - *window_started >= DECODE_ERROR_WINDOW {
+        *errors = 0;
+        *window_started = now;
+    }
+    *errors += 1;
+    warn!(
+        "Dropped undecodable message from player {} ({} bytes, {} errors): {}",
+        client_id, message_len, errors, error
+    );
+    if *errors >= server_settings.max_decode_errors {
+        warn!("Disconnecting player {} after {} undecodable messages", client_id, errors);
+        server.disconnect(client_id);
+        return true;
+    }
+    false
+}
+
+/// System to disconnect rejected clients once their rejection message had time to be delivered
+fn disconnect_rejected_system(mut server: ResMut<RenetServer>, rejected_clients: Res<RejectedClients>, time: Res<Time>) {
+    for (&client_id, &rejected_time) in rejected_clients.clients.iter() {
+        if time.elapsed_secs_f64() - rejected_time > REJECT_DISCONNECT_DELAY {
+            server.disconnect(client_id);
         }
     }
 }
 
 /// System to cleanup disconnected entities after a number of seconds
+#[allow(clippy::too_many_arguments)]
 fn cleanup_disconnected_system(
     mut commands: Commands,
     time: Res<Time>,
     mut lobby: ResMut<Lobby>,
-    query: Query<(Entity, &Disconnected)>,
+    mut sessions: ResMut<Sessions>,
+    mut selected_colors: ResMut<SelectedColors>,
+    query: Query<(Entity, &Disconnected, Option<&Session>)>,
     server_settings: Res<ServerSettings>,
+    store: Option<Res<PlayerStore>>,
 ) {
-    for (entity, disconnected) in query.iter() {
+    for (entity, disconnected, session) in query.iter() {
         if time.elapsed_secs_f64() - disconnected.disconnect_time > server_settings.client_disconnect_grace_period {
             let client_id_opt = lobby.players.iter().find_map(|(id, &e)| if e == entity { Some(*id) } else { None });
             if let Some(client_id) = client_id_opt {
                 lobby.players.remove(&client_id);
+                selected_colors.release(client_id);
+            }
+            if let Some(Session(token)) = session {
+                sessions.players.remove(token);
+                if let Some(store) = &store {
+                    store.remove_owner(*token);
+                }
             }
             commands.entity(entity).despawn();
             info!(
@@ -275,30 +883,387 @@ fn cleanup_disconnected_system(
     }
 }
 
-/// System to sync player positions to clients
-fn server_sync_players(mut server: ResMut<RenetServer>, query: Query<(&Transform, &Player, &PlayerColor)>) {
-    let mut players: HashMap<ClientId, ([f32; 3], [f32; 4])> = HashMap::new();
-    for (transform, player, player_color) in query.iter() {
-        players.insert(player.id, (transform.translation.into(), player_color.0));
+fn player_state(transform: &Transform, color: &PlayerColor) -> PlayerState {
+    PlayerState {
+        translation: transform.translation,
+        color: color.0,
+    }
+}
+
+/// Seconds since the Unix epoch, leases are compared across replicas so they use wall clock time
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// This replica as the owner of a session until `expires_at`
+fn session_owner(settings: &ServerSettings, client_id: ClientId, expires_at: u64) -> SessionOwner {
+    SessionOwner {
+        replica: settings.replica_id.clone(),
+        address: settings.public_address(),
+        client_id,
+        expires_at,
+    }
+}
+
+/// Record this replica as the owner of a session until `expires_at`
+fn claim_session(store: &PlayerStore, settings: &ServerSettings, token: &SessionToken, client_id: ClientId, expires_at: u64) {
+    store.write(Batch {
+        owners: vec![(*token, session_owner(settings, client_id, expires_at))],
+        ..default()
+    });
+}
+
+/// System to save players as soon as they disconnect
+fn save_disconnected_players_system(store: Res<PlayerStore>, query: Query<(&Player, &Transform, &PlayerColor), Added<Disconnected>>) {
+    store.write(Batch {
+        states: query
+            .iter()
+            .map(|(player, transform, color)| (player.id, player_state(transform, color)))
+            .collect(),
+        ..default()
+    });
+}
+
+/// What is saved and renewed for a player, and whether it is waiting out its grace period
+type SavedPlayer = (
+    &'static Player,
+    &'static Transform,
+    &'static PlayerColor,
+    Option<&'static Session>,
+    Has<Disconnected>,
+);
+
+/// System to save every connected player and renew the lease on every session this replica holds, in one write,
+/// so little is lost if the server dies without a clean shutdown and peers leave its players alone
+fn save_players_system(store: Res<PlayerStore>, settings: Res<ServerSettings>, query: Query<SavedPlayer>) {
+    let expires_at = settings.lease_expiry();
+    let mut batch = Batch::default();
+    for (player, transform, color, session, disconnected) in query.iter() {
+        // Players in their grace period were saved when they left and have not moved since
+        if !disconnected {
+            batch.states.push((player.id, player_state(transform, color)));
+        }
+        if let Some(Session(token)) = session {
+            batch.owners.push((*token, session_owner(&settings, player.id, expires_at)));
+        }
+    }
+    if !store.pass(batch) {
+        warn!("Skipping a save, the store has not finished the previous one");
+    }
+}
+
+/// System to note when a tick starts, for the tick duration metric
+fn start_tick_system(mut tick_timing: ResMut<TickTiming>) {
+    tick_timing.start();
+}
+
+/// System to record how long a tick took
+fn finish_tick_system(mut tick_timing: ResMut<TickTiming>) {
+    tick_timing.finish();
+}
+
+/// System to copy the latest metrics where the HTTP thread can read them
+#[allow(clippy::too_many_arguments)]
+fn publish_metrics_system(
+    server: Res<RenetServer>,
+    lobby: Res<Lobby>,
+    disconnected: Query<(), With<Disconnected>>,
+    traffic: Res<ChannelTraffic>,
+    tick_timing: Res<TickTiming>,
+    transport_errors: Res<TransportErrors>,
+    server_settings: Res<ServerSettings>,
+    metrics: Res<SharedMetrics>,
+) {
+    let snapshot = MetricsSnapshot {
+        max_clients: server_settings.max_clients,
+        connected_clients: server.connected_clients(),
+        lobby_players: lobby.players.len(),
+        disconnected_players: disconnected.iter().count(),
+        tick_timing: tick_timing.clone(),
+        traffic: traffic.clone(),
+        transport_errors: transport_errors.0,
+        clients: server
+            .clients_id_iter()
+            .filter_map(|id| server.network_info(id).ok().map(|info| (id, info)))
+            .map(|(id, info)| ClientNetwork {
+                id,
+                rtt: info.rtt,
+                packet_loss: info.packet_loss,
+            })
+            .collect(),
+        published: true,
+    };
+    *metrics.0.lock().unwrap() = snapshot;
+}
+
+/// System to refresh what the health probes report
+fn publish_health_system(
+    server: Res<RenetServer>,
+    tick_timing: Res<TickTiming>,
+    shutting_down: Option<Res<ShuttingDown>>,
+    server_settings: Res<ServerSettings>,
+    health: Res<SharedHealth>,
+) {
+    let mut health = health.0.lock().unwrap();
+    if let Some(finished_at) = tick_timing.finished_at {
+        health.last_tick = finished_at;
+    }
+    health.watchdog_timeout = Duration::from_secs_f64(server_settings.tick_watchdog_timeout);
+    health.at_capacity = server.connected_clients() >= server_settings.max_clients as usize;
+    health.draining = shutting_down.is_some();
+    health.published = true;
+}
+
+/// System to apply the gameplay settings of a changed config file, the others only take effect on restart
+fn reload_config_system(
+    mut watch: ResMut<ConfigWatch>,
+    mut settings: ResMut<ServerSettings>,
+    mut server: ResMut<RenetServer>,
+    mut traffic: ResMut<ChannelTraffic>,
+) {
+    let reloaded = match watch.reload() {
+        Some(Ok(reloaded)) => reloaded,
+        Some(Err(e)) => {
+            warn!("Keeping the current settings, the changed config is invalid: {}", e);
+            return;
+        }
+        None => return,
+    };
+    let simulation = settings.simulation();
+    let changes = settings.apply_reloadable(&reloaded);
+    if changes.is_empty() {
+        info!("Config file changed, no setting that can be reloaded did");
+        return;
+    }
+    info!("Reloaded config: {}", changes.join(", "));
+    // Only the simulation is broadcast, clients need it to predict movement. The other reloadable settings are
+    // the server's own: palette_mode only affects colors picked from now on, and a new interest_radius reaches
+    // clients as the players entering and leaving it
+    if settings.simulation() != simulation {
+        let message = bincode::serialize(&ServerMessages::SimulationChanged {
+            settings: settings.simulation(),
+        })
+        .unwrap();
+        traffic.broadcast(&mut server, DefaultChannel::ReliableOrdered, message);
     }
-    let sync_message = bincode::serialize(&players).unwrap();
-    server.broadcast_message(DefaultChannel::Unreliable, sync_message);
 }
 
-/// System to move player entities based on input
+/// System to apply exactly one buffered input per player each tick, holding the last input when none arrived
+fn apply_inputs_system(mut query: Query<(&mut PlayerInput, &mut InputBuffer, Has<Disconnected>)>, mut tick: ResMut<ServerTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+    for (mut input, mut buffer, disconnected) in query.iter_mut() {
+        if disconnected {
+            *input = PlayerInput::default();
+        } else if let Some(command) = buffer.pending.pop_front() {
+            *input = command.input;
+            buffer.last_applied_sequence = command.sequence;
+        }
+    }
+}
+
+/// System to sync player positions to clients.
+/// Each client only gets the players near its own, as a delta against the last snapshot it acknowledged.
+#[allow(clippy::too_many_arguments)]
+fn server_sync_players(
+    mut server: ResMut<RenetServer>,
+    mut traffic: ResMut<ChannelTraffic>,
+    mut snapshot_senders: ResMut<SnapshotSenders>,
+    mut interests: ResMut<ClientInterests>,
+    lobby: Res<Lobby>,
+    query: Query<(&Transform, &Player, &InputBuffer, Has<Disconnected>)>,
+    profiles: Query<(&PlayerColor, Option<&PlayerName>)>,
+    tick: Res<ServerTick>,
+    server_settings: Res<ServerSettings>,
+) {
+    let mut grid = SpatialGrid::new(server_settings.interest_radius);
+    for (transform, player, _, disconnected) in query.iter() {
+        if !disconnected {
+            grid.insert(player.id, transform.translation);
+        }
+    }
+
+    for client_id in server.clients_id() {
+        let Some((transform, _, input_buffer, _)) = lobby.players.get(&client_id).and_then(|&entity| query.get(entity).ok()) else {
+            continue;
+        };
+        let nearby: Vec<(ClientId, Vec3)> = grid.nearby(transform.translation).collect();
+
+        let (entered, left) = interests.update(client_id, nearby.iter().map(|&(id, _)| id).collect::<HashSet<_>>());
+        for id in left {
+            let message = bincode::serialize(&ServerMessages::DespawnPlayer { id }).unwrap();
+            traffic.send(&mut server, client_id, DefaultChannel::ReliableOrdered, message);
+        }
+        for id in entered {
+            if let Some((player_color, name)) = lobby.players.get(&id).and_then(|&entity| profiles.get(entity).ok()) {
+                let message = bincode::serialize(&ServerMessages::SpawnPlayer {
+                    id,
+                    color: player_color.0,
+                    name: name.map(|name| name.0.clone()),
+                })
+                .unwrap();
+                traffic.send(&mut server, client_id, DefaultChannel::ReliableOrdered, message);
+            }
+        }
+
+        let state: WorldState = nearby.iter().map(|&(id, translation)| (id, quantize(translation))).collect();
+        let snapshot = snapshot_senders
+            .clients
+            .entry(client_id)
+            .or_default()
+            .encode(tick.0, &state, input_buffer.last_applied_sequence);
+        traffic.send(&mut server, client_id, DefaultChannel::Unreliable, snapshot.to_bytes());
+    }
+}
+
+/// System to move player entities based on input, once per tick
 fn move_players_system(mut query: Query<(&mut Transform, &PlayerInput)>, time: Res<Time>, server_settings: Res<ServerSettings>) {
     for (mut transform, input) in query.iter_mut() {
-        let x = (input.right as i8 - input.left as i8) as f32;
-        let y = (input.down as i8 - input.up as i8) as f32;
-        transform.translation.x += x * server_settings.player_move_speed * time.delta().as_secs_f32();
-        transform.translation.z += y * server_settings.player_move_speed * time.delta().as_secs_f32();
+        move_player(
+            &mut transform.translation,
+            input,
+            server_settings.player_move_speed,
+            time.delta().as_secs_f32(),
+        );
     }
 }
 
-/// If any error is found we just panic
-#[allow(clippy::never_loop)]
-fn panic_on_error_system(mut renet_error: EventReader<NetcodeTransportError>) {
-    for e in renet_error.read() {
-        panic!("{}", e);
+/// Decide whether a transport error concerns a single client or the whole server
+fn classify_transport_error(error: &NetcodeTransportError) -> TransportErrorAction {
+    use std::io::ErrorKind;
+
+    let io_error = match error {
+        NetcodeTransportError::IO(e) => e,
+        NetcodeTransportError::Netcode(NetcodeError::IoError(e)) => e,
+        NetcodeTransportError::Netcode(_) | NetcodeTransportError::Renet(_) => return TransportErrorAction::Count,
+    };
+    match io_error.kind() {
+        // ICMP errors from a peer that went away are reported on the next recv_from
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::TimedOut
+        | ErrorKind::WouldBlock
+        | ErrorKind::Interrupted => TransportErrorAction::Count,
+        _ => TransportErrorAction::Exit,
+    }
+}
+
+/// System to handle transport errors without taking down every client on the server.
+///
+/// The transport only reports errors it cannot attribute to a client, so no client is disconnected for them.
+/// A fatal error shuts the server down like a signal does, only sooner and with a failure exit code.
+fn transport_error_system(
+    mut commands: Commands,
+    mut transport_errors: EventReader<NetcodeTransportError>,
+    mut errors: ResMut<TransportErrors>,
+    mut server: ResMut<RenetServer>,
+    mut traffic: ResMut<ChannelTraffic>,
+    shutting_down: Option<ResMut<ShuttingDown>>,
+    time: Res<Time>,
+) {
+    for error in transport_errors.read() {
+        match classify_transport_error(error) {
+            TransportErrorAction::Count => {
+                errors.0 += 1;
+                warn!("Transport error: {}", error);
+            }
+            TransportErrorAction::Exit => {
+                // The warning may not get through, clients that miss it find out through their own timeouts
+                error!("Fatal transport error, saving players and exiting: {}", error);
+                let now = time.elapsed_secs_f64();
+                match shutting_down {
+                    Some(mut shutting_down) => {
+                        shutting_down.delay = shutting_down.delay.min(now - shutting_down.started + FATAL_ERROR_SHUTDOWN_DELAY);
+                        shutting_down.exit = AppExit::from_code(1);
+                    }
+                    None => start_shutdown(
+                        &mut commands,
+                        &mut server,
+                        &mut traffic,
+                        now,
+                        FATAL_ERROR_SHUTDOWN_DELAY,
+                        AppExit::from_code(1),
+                    ),
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// System to start draining once SIGTERM or SIGINT was received
+fn signal_system(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut traffic: ResMut<ChannelTraffic>,
+    signal: Res<ShutdownSignal>,
+    shutting_down: Option<Res<ShuttingDown>>,
+    settings: Res<ServerSettings>,
+    time: Res<Time>,
+) {
+    if shutting_down.is_some() || !signal.0.load(Ordering::SeqCst) {
+        return;
+    }
+    info!(
+        "Termination requested, draining {} clients for {}s",
+        server.connected_clients(),
+        settings.drain_period
+    );
+    start_shutdown(
+        &mut commands,
+        &mut server,
+        &mut traffic,
+        time.elapsed_secs_f64(),
+        settings.drain_period,
+        AppExit::Success,
+    );
+}
+
+/// Tell every client the server is going away, then let `shutdown_system` exit with `exit` after `delay` seconds
+fn start_shutdown(commands: &mut Commands, server: &mut RenetServer, traffic: &mut ChannelTraffic, now: f64, delay: f64, exit: AppExit) {
+    let message = bincode::serialize(&ServerMessages::ServerShuttingDown {
+        reconnect_hint: Some(SHUTDOWN_RECONNECT_HINT),
+    })
+    .unwrap();
+    traffic.broadcast(server, DefaultChannel::ReliableOrdered, message);
+    commands.insert_resource(ShuttingDown { started: now, delay, exit });
+}
+
+/// System to exit once clients had a chance to receive `ServerMessages::ServerShuttingDown`
+#[allow(clippy::too_many_arguments)]
+fn shutdown_system(
+    mut server: ResMut<RenetServer>,
+    mut transport: ResMut<NetcodeServerTransport>,
+    shutting_down: Option<Res<ShuttingDown>>,
+    mut exit: EventWriter<AppExit>,
+    store: Option<Res<PlayerStore>>,
+    settings: Res<ServerSettings>,
+    players: Query<(&Player, &Transform, &PlayerColor, Option<&Session>)>,
+    time: Res<Time>,
+) {
+    let Some(shutting_down) = shutting_down else {
+        return;
+    };
+    if time.elapsed_secs_f64() - shutting_down.started > shutting_down.delay {
+        if let Some(store) = store {
+            let mut batch = Batch::default();
+            for (player, transform, color, session) in players.iter() {
+                batch.states.push((player.id, player_state(transform, color)));
+                // An already expired lease lets the next replica the client reaches take the player over right away
+                if let Some(Session(token)) = session {
+                    batch.owners.push((*token, session_owner(&settings, player.id, unix_time())));
+                }
+            }
+            store.write(batch);
+            if !store.flush(STORE_FLUSH_TIMEOUT) {
+                warn!("Exiting before the store saved every player");
+            }
+        }
+        info!("Disconnecting {} clients and shutting down", server.connected_clients());
+        transport.disconnect_all(&mut server);
+        exit.send(shutting_down.exit.clone());
     }
 }
@@ -0,0 +1,192 @@
+//! Game metrics in the Prometheus text format.
+//!
+//! Systems keep counters in Bevy resources, `publish_metrics_system` copies
+//! them into [`SharedMetrics`] every frame, and the HTTP thread renders the latest copy when scraped.
+
+use bevy::prelude::*;
+use bevy_renet::renet::{Bytes, ClientId, DefaultChannel, RenetServer};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Metric label of each channel, indexed by the id `DefaultChannel` converts to
+const CHANNEL_LABELS: [&str; 3] = ["unreliable", "reliable_unordered", "reliable_ordered"];
+
+/// Message bytes sent and received on each channel since startup, indexed by channel id
+#[derive(Debug, Clone, Default, Resource)]
+pub struct ChannelTraffic {
+    sent: [u64; CHANNEL_LABELS.len()],
+    received: [u64; CHANNEL_LABELS.len()],
+}
+
+impl ChannelTraffic {
+    /// Send a message to one client and count its bytes
+    pub fn send(&mut self, server: &mut RenetServer, client_id: ClientId, channel: DefaultChannel, message: Vec<u8>) {
+        let channel = u8::from(channel);
+        self.sent[channel as usize] += message.len() as u64;
+        server.send_message(client_id, channel, message);
+    }
+
+    /// Send a message to every client and count its bytes once per client
+    pub fn broadcast(&mut self, server: &mut RenetServer, channel: DefaultChannel, message: Vec<u8>) {
+        let channel = u8::from(channel);
+        self.sent[channel as usize] += (message.len() * server.connected_clients()) as u64;
+        server.broadcast_message(channel, message);
+    }
+
+    /// Receive a message from a client and count its bytes
+    pub fn receive(&mut self, server: &mut RenetServer, client_id: ClientId, channel: DefaultChannel) -> Option<Bytes> {
+        let channel = u8::from(channel);
+        let message = server.receive_message(client_id, channel)?;
+        self.received[channel as usize] += message.len() as u64;
+        Some(message)
+    }
+}
+
+/// Wall clock time spent simulating ticks
+#[derive(Debug, Clone, Default, Resource)]
+pub struct TickTiming {
+    started: Option<Instant>,
+    pub finished_at: Option<Instant>,
+    pub ticks: u64,
+    pub last: Duration,
+    pub total: Duration,
+}
+
+impl TickTiming {
+    pub fn start(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(started) = self.started.take() {
+            let finished_at = Instant::now();
+            self.last = finished_at - started;
+            self.total += self.last;
+            self.finished_at = Some(finished_at);
+            self.ticks += 1;
+        }
+    }
+}
+
+/// Network statistics of one connected client
+#[derive(Debug, Clone)]
+pub struct ClientNetwork {
+    pub id: ClientId,
+    pub rtt: f64,
+    pub packet_loss: f64,
+}
+
+/// Values reported on `/metrics`
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub max_clients: u32,
+    pub connected_clients: usize,
+    pub lobby_players: usize,
+    pub disconnected_players: usize,
+    pub tick_timing: TickTiming,
+    pub traffic: ChannelTraffic,
+    /// Transport errors that could not be attributed to a client
+    pub transport_errors: u64,
+    pub clients: Vec<ClientNetwork>,
+    /// False until the app publishes its first snapshot, the defaults describe no server
+    pub published: bool,
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (suffix, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, suffix, value);
+            }
+        };
+        let value = |value: &dyn ToString| vec![(String::new(), value.to_string())];
+
+        metric("game_max_clients", "gauge", "Clients the server accepts", &value(&self.max_clients));
+        metric(
+            "game_connected_clients",
+            "gauge",
+            "Clients connected to the transport",
+            &value(&self.connected_clients),
+        );
+        metric(
+            "game_lobby_players",
+            "gauge",
+            "Players in the lobby, including those in their disconnect grace period",
+            &value(&self.lobby_players),
+        );
+        metric(
+            "game_disconnected_players",
+            "gauge",
+            "Players waiting out their disconnect grace period",
+            &value(&self.disconnected_players),
+        );
+        metric(
+            "game_tick_duration_seconds",
+            "summary",
+            "Time spent simulating a tick",
+            &[
+                ("_sum".to_string(), self.tick_timing.total.as_secs_f64().to_string()),
+                ("_count".to_string(), self.tick_timing.ticks.to_string()),
+            ],
+        );
+        metric(
+            "game_last_tick_duration_seconds",
+            "gauge",
+            "Time spent simulating the latest tick",
+            &value(&self.tick_timing.last.as_secs_f64()),
+        );
+        let per_channel = |counts: &[u64; CHANNEL_LABELS.len()]| {
+            CHANNEL_LABELS
+                .iter()
+                .zip(counts)
+                .map(|(label, count)| (format!("{{channel=\"{}\"}}", label), count.to_string()))
+                .collect::<Vec<_>>()
+        };
+        metric(
+            "game_bytes_sent_total",
+            "counter",
+            "Message bytes sent per channel",
+            &per_channel(&self.traffic.sent),
+        );
+        metric(
+            "game_bytes_received_total",
+            "counter",
+            "Message bytes received per channel",
+            &per_channel(&self.traffic.received),
+        );
+        metric(
+            "game_transport_errors_total",
+            "counter",
+            "Socket errors the transport could not attribute to a client",
+            &value(&self.transport_errors),
+        );
+        let per_client = |value: fn(&ClientNetwork) -> f64| {
+            self.clients
+                .iter()
+                .map(|client| (format!("{{client_id=\"{}\"}}", client.id), value(client).to_string()))
+                .collect::<Vec<_>>()
+        };
+        metric(
+            "game_client_rtt_seconds",
+            "gauge",
+            "Round trip time of each client",
+            &per_client(|client| client.rtt),
+        );
+        metric(
+            "game_client_packet_loss_ratio",
+            "gauge",
+            "Fraction of packets to each client that were lost",
+            &per_client(|client| client.packet_loss),
+        );
+        out
+    }
+}
+
+/// Latest metrics, shared between the Bevy app and the HTTP thread
+#[derive(Debug, Clone, Default, Resource)]
+pub struct SharedMetrics(pub Arc<Mutex<MetricsSnapshot>>);
@@ -0,0 +1,54 @@
+//! Checks on the names and colors players pick for themselves.
+
+use crate::colors::{self, SelectedColors};
+use bevy_renet::renet::ClientId;
+use protocol::{MAX_NAME_CHARS, PaletteMode, ProfileRejection};
+
+/// Words a name may not contain, matched against the name's letters with common digit substitutions undone
+const BLOCKED_WORDS: [&str; 12] = [
+    "fuck", "shit", "cunt", "bitch", "nigger", "nigga", "faggot", "asshole", "bastard", "whore", "slut", "retard",
+];
+
+/// The name to use for `name`, trimmed
+pub fn validate_name(name: &str) -> Result<String, ProfileRejection> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(ProfileRejection::NameLength);
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.'))
+    {
+        return Err(ProfileRejection::NameCharacters);
+    }
+    // Separators and digits are easy ways around a plain substring match
+    let letters: String = name
+        .chars()
+        .filter_map(|c| match c.to_ascii_lowercase() {
+            '0' => Some('o'),
+            '1' => Some('i'),
+            '3' => Some('e'),
+            '4' => Some('a'),
+            '5' => Some('s'),
+            '7' => Some('t'),
+            c if c.is_ascii_lowercase() => Some(c),
+            _ => None,
+        })
+        .collect();
+    if BLOCKED_WORDS.iter().any(|word| letters.contains(word)) {
+        return Err(ProfileRejection::NameNotAllowed);
+    }
+    Ok(name.to_string())
+}
+
+/// The color to use for player `id` asking for `color`, opaque and far enough from every other player's
+pub fn validate_color(color: [f32; 4], selected: &SelectedColors, id: ClientId, mode: PaletteMode) -> Result<[f32; 4], ProfileRejection> {
+    if !color.iter().all(|c| (0.0..=1.0).contains(c)) {
+        return Err(ProfileRejection::InvalidColor);
+    }
+    let color = [color[0], color[1], color[2], 1.0];
+    if !colors::is_distinct(selected, id, &color, mode) {
+        return Err(ProfileRejection::ColorTaken);
+    }
+    Ok(color)
+}
@@ -0,0 +1,477 @@
+//! Persistence of player state, so a restarted server puts returning players back where they were.
+//!
+//! [`open`] picks a backend from the `STATE_STORE` setting: `file:<dir>` keeps one file per player,
+//! `redis://<host>:<port>` talks to anything that speaks the Redis protocol.
+//!
+//! When replicas share a store they also share session ownership: each session token maps to the replica
+//! holding that player, so a client that reconnects through the load balancer can be sent back to it.
+//!
+//! The store is only used from its own thread, see [`PlayerStore`], so a slow or unreachable Redis never holds up a tick.
+
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+use protocol::session::SessionToken;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a Redis command may take before the store gives up on it
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What is remembered about a player between connections
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub translation: Vec3,
+    pub color: [f32; 4],
+}
+
+/// Replica holding the player behind a session token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionOwner {
+    /// Replica id of the owner, addresses can be shared or reused so they do not tell replicas apart
+    pub replica: String,
+    /// Address clients reach the owning replica at
+    pub address: SocketAddr,
+    /// Client id the player's state is saved under
+    pub client_id: ClientId,
+    /// Unix time in seconds after which the owner is presumed dead, unless it renews the lease
+    pub expires_at: u64,
+}
+
+impl SessionOwner {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// Player states to save and sessions to claim, written together
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub states: Vec<(ClientId, PlayerState)>,
+    pub owners: Vec<(SessionToken, SessionOwner)>,
+}
+
+impl Batch {
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty() && self.owners.is_empty()
+    }
+}
+
+/// Somewhere player state outlives the server process
+pub trait StateStore: Send + Sync {
+    /// State saved for a client id, `None` if it was never saved
+    fn load(&self, client_id: ClientId) -> io::Result<Option<PlayerState>>;
+
+    fn save(&self, client_id: ClientId, state: &PlayerState) -> io::Result<()>;
+
+    /// Replica that last claimed a session, `None` if nobody did or it was released
+    fn owner(&self, token: &SessionToken) -> io::Result<Option<SessionOwner>>;
+
+    fn set_owner(&self, token: &SessionToken, owner: &SessionOwner) -> io::Result<()>;
+
+    fn remove_owner(&self, token: &SessionToken) -> io::Result<()>;
+
+    /// Save and claim everything in `batch`, stores that can should do it in one round trip
+    fn write(&self, batch: &Batch) -> io::Result<()> {
+        for (client_id, state) in &batch.states {
+            self.save(*client_id, state)?;
+        }
+        for (token, owner) in &batch.owners {
+            self.set_owner(token, owner)?;
+        }
+        Ok(())
+    }
+}
+
+/// Work for the store thread, done in the order it was queued
+enum Job {
+    Write(Batch),
+    /// A periodic save of every player, at most one is queued at a time
+    Pass(Batch),
+    RemoveOwner(SessionToken),
+    Lookup {
+        client_id: ClientId,
+        session: Option<SessionToken>,
+    },
+    /// Answered once every job queued before it is done
+    Flush(Sender<()>),
+}
+
+/// What the store knows about a joining client, see [`PlayerStore::lookup`]
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub client_id: ClientId,
+    /// Replica that last claimed the session the client presented
+    pub owner: Option<SessionOwner>,
+    /// State saved under the client id the owner knew the player by, or under the client's own id without an owner
+    pub saved: Option<PlayerState>,
+}
+
+/// The store the server was configured with, absent when state is kept in memory only.
+///
+/// Every call queues a job for the store thread and returns right away, lookups are answered through [`PlayerStore::lookups`].
+#[derive(Resource)]
+pub struct PlayerStore {
+    jobs: Sender<Job>,
+    lookups: Mutex<Receiver<Lookup>>,
+    /// Set while a periodic pass is queued or being written
+    pass_pending: Arc<AtomicBool>,
+}
+
+impl PlayerStore {
+    /// Start the store thread
+    pub fn spawn(store: Box<dyn StateStore>) -> Self {
+        let (jobs, queued) = mpsc::channel();
+        let (answer, lookups) = mpsc::channel();
+        let pass_pending = Arc::new(AtomicBool::new(false));
+        let pending = pass_pending.clone();
+        thread::spawn(move || {
+            for job in queued {
+                match job {
+                    Job::Write(batch) => write(store.as_ref(), &batch),
+                    Job::Pass(batch) => {
+                        write(store.as_ref(), &batch);
+                        pending.store(false, Ordering::SeqCst);
+                    }
+                    Job::RemoveOwner(token) => {
+                        if let Err(e) = store.remove_owner(&token) {
+                            warn!("Failed to release a session: {}", e);
+                        }
+                    }
+                    Job::Lookup { client_id, session } => {
+                        let _ = answer.send(lookup(store.as_ref(), client_id, session));
+                    }
+                    Job::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Self {
+            jobs,
+            lookups: Mutex::new(lookups),
+            pass_pending,
+        }
+    }
+
+    fn queue(&self, job: Job) {
+        // The thread only stops when its store panicked, which already got logged
+        let _ = self.jobs.send(job);
+    }
+
+    pub fn write(&self, batch: Batch) {
+        if !batch.is_empty() {
+            self.queue(Job::Write(batch));
+        }
+    }
+
+    /// Queue a periodic save, returns false without queueing it while the previous one is not written yet
+    pub fn pass(&self, batch: Batch) -> bool {
+        if self.pass_pending.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.queue(Job::Pass(batch));
+        true
+    }
+
+    pub fn remove_owner(&self, token: SessionToken) {
+        self.queue(Job::RemoveOwner(token));
+    }
+
+    /// Look up the owner of `session` and the state to restore a joining client with, answered by [`PlayerStore::lookups`]
+    pub fn lookup(&self, client_id: ClientId, session: Option<SessionToken>) {
+        self.queue(Job::Lookup { client_id, session });
+    }
+
+    /// Lookups answered since the last call
+    pub fn lookups(&self) -> Vec<Lookup> {
+        self.lookups.lock().unwrap().try_iter().collect()
+    }
+
+    /// Wait up to `timeout` for every queued job to be done, returns false if they were not
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let (done, finished) = mpsc::channel();
+        self.queue(Job::Flush(done));
+        finished.recv_timeout(timeout).is_ok()
+    }
+}
+
+/// Failures only cost players their position or session on the next restart, so they are logged and dropped
+fn write(store: &dyn StateStore, batch: &Batch) {
+    if let Err(e) = store.write(batch) {
+        warn!(
+            "Failed to save {} players and {} sessions: {}",
+            batch.states.len(),
+            batch.owners.len(),
+            e
+        );
+    }
+}
+
+fn lookup(store: &dyn StateStore, client_id: ClientId, session: Option<SessionToken>) -> Lookup {
+    let owner = session.and_then(|token| {
+        store.owner(&token).unwrap_or_else(|e| {
+            warn!("Failed to look up the session owner of player {}: {}", client_id, e);
+            None
+        })
+    });
+    // Taking over from an owner that is gone, its state is saved under the client id it knew
+    let state_key = owner.as_ref().map_or(client_id, |owner| owner.client_id);
+    let saved = store.load(state_key).unwrap_or_else(|e| {
+        warn!("Failed to load the state of player {}: {}", client_id, e);
+        None
+    });
+    Lookup { client_id, owner, saved }
+}
+
+/// Open the store described by a `file:<dir>` or `redis://<host>:<port>` url
+pub fn open(url: &str) -> io::Result<Box<dyn StateStore>> {
+    if let Some(dir) = url.strip_prefix("file:") {
+        Ok(Box::new(FileStore::new(dir)?))
+    } else if let Some(address) = url.strip_prefix("redis://") {
+        Ok(Box::new(RedisStore::new(address.trim_end_matches('/'))))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown state store {:?}, expected file:<dir> or redis://<host>:<port>", url),
+        ))
+    }
+}
+
+fn encode(value: &impl Serialize) -> Vec<u8> {
+    bincode::serialize(value).unwrap()
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// One file per player in a directory, for a single server with a persistent volume
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Use `dir`, creating it if needed
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn read<T: DeserializeOwned>(&self, name: &str) -> io::Result<Option<T>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(bytes) => decode(&bytes).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(&self, name: &str, value: &impl Serialize) -> io::Result<()> {
+        // Write then rename, so a crash mid-write never leaves a truncated file behind
+        let path = self.dir.join(name);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, encode(value))?;
+        fs::rename(temporary, path)
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self, client_id: ClientId) -> io::Result<Option<PlayerState>> {
+        self.read(&format!("player-{}.bin", client_id))
+    }
+
+    fn save(&self, client_id: ClientId, state: &PlayerState) -> io::Result<()> {
+        self.write(&format!("player-{}.bin", client_id), state)
+    }
+
+    fn owner(&self, token: &SessionToken) -> io::Result<Option<SessionOwner>> {
+        self.read(&format!("session-{}.bin", token.to_hex()))
+    }
+
+    fn set_owner(&self, token: &SessionToken, owner: &SessionOwner) -> io::Result<()> {
+        self.write(&format!("session-{}.bin", token.to_hex()), owner)
+    }
+
+    fn remove_owner(&self, token: &SessionToken) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(format!("session-{}.bin", token.to_hex()))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A reply in the Redis serialization protocol (RESP)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    /// `None` for the null bulk string Redis answers a missing key with
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// Write a command as an array of bulk strings
+pub fn write_command(writer: &mut impl Write, args: &[&[u8]]) -> io::Result<()> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    writer.write_all(&command)
+}
+
+/// Read one reply, or one command when acting as the server
+pub fn read_reply(reader: &mut impl BufRead) -> io::Result<Reply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid reply line {:?}", line));
+    let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+    let length = || rest.parse::<i64>().map_err(|_| invalid());
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Ok(Reply::Error(rest.to_string())),
+        ":" => Ok(Reply::Integer(length()?)),
+        "$" => {
+            let Ok(length) = usize::try_from(length()?) else {
+                return Ok(Reply::Bulk(None));
+            };
+            let mut data = vec![0; length + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(length);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        "*" => {
+            let count = usize::try_from(length()?).unwrap_or_default();
+            (0..count).map(|_| read_reply(reader)).collect::<io::Result<_>>().map(Reply::Array)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Player state kept under `player:<client id>` keys of a Redis compatible server, session owners under `session:<token>`
+pub struct RedisStore {
+    address: String,
+    /// Opened on first use and dropped after an error, so the store recovers when Redis comes back
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisStore {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let address = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", self.address)))?;
+        let stream = TcpStream::connect_timeout(&address, REDIS_TIMEOUT)?;
+        stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        stream.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Send a command and wait for its reply, error replies become `Err`
+    pub fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let args = args.iter().map(|arg| arg.to_vec()).collect();
+        self.pipeline(&[args]).map(|mut replies| replies.remove(0))
+    }
+
+    /// Send commands in a single write and wait for every reply, the first error reply becomes `Err`
+    pub fn pipeline(&self, commands: &[Vec<Vec<u8>>]) -> io::Result<Vec<Reply>> {
+        let mut connection = self.connection.lock().unwrap();
+        let result = (|| {
+            if connection.is_none() {
+                *connection = Some(self.connect()?);
+            }
+            let reader = connection.as_mut().unwrap();
+            let mut buffer = Vec::new();
+            for args in commands {
+                write_command(&mut buffer, &args.iter().map(Vec::as_slice).collect::<Vec<_>>())?;
+            }
+            reader.get_mut().write_all(&buffer)?;
+            commands.iter().map(|_| read_reply(reader)).collect::<io::Result<Vec<_>>>()
+        })();
+        if result.is_err() {
+            *connection = None;
+        }
+        let replies = result?;
+        if let Some(Reply::Error(message)) = replies.iter().find(|reply| matches!(reply, Reply::Error(_))) {
+            return Err(io::Error::other(message.clone()));
+        }
+        Ok(replies)
+    }
+
+    fn get<T: DeserializeOwned>(&self, key: &str) -> io::Result<Option<T>> {
+        match self.command(&[b"GET", key.as_bytes()])? {
+            Reply::Bulk(Some(bytes)) => decode(&bytes).map(Some),
+            Reply::Bulk(None) => Ok(None),
+            reply => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", reply))),
+        }
+    }
+
+    fn set(&self, key: &str, value: &impl Serialize) -> io::Result<()> {
+        self.command(&[b"SET", key.as_bytes(), &encode(value)])?;
+        Ok(())
+    }
+}
+
+impl StateStore for RedisStore {
+    fn load(&self, client_id: ClientId) -> io::Result<Option<PlayerState>> {
+        self.get(&format!("player:{}", client_id))
+    }
+
+    fn save(&self, client_id: ClientId, state: &PlayerState) -> io::Result<()> {
+        self.set(&format!("player:{}", client_id), state)
+    }
+
+    fn owner(&self, token: &SessionToken) -> io::Result<Option<SessionOwner>> {
+        self.get(&format!("session:{}", token.to_hex()))
+    }
+
+    fn set_owner(&self, token: &SessionToken, owner: &SessionOwner) -> io::Result<()> {
+        self.set(&format!("session:{}", token.to_hex()), owner)
+    }
+
+    fn remove_owner(&self, token: &SessionToken) -> io::Result<()> {
+        self.command(&[b"DEL", format!("session:{}", token.to_hex()).as_bytes()])?;
+        Ok(())
+    }
+
+    fn write(&self, batch: &Batch) -> io::Result<()> {
+        let set = |key: String, value: Vec<u8>| vec![b"SET".to_vec(), key.into_bytes(), value];
+        let commands: Vec<_> = batch
+            .states
+            .iter()
+            .map(|(client_id, state)| set(format!("player:{}", client_id), encode(state)))
+            .chain(
+                batch
+                    .owners
+                    .iter()
+                    .map(|(token, owner)| set(format!("session:{}", token.to_hex()), encode(owner))),
+            )
+            .collect();
+        if !commands.is_empty() {
+            self.pipeline(&commands)?;
+        }
+        Ok(())
+    }
+}
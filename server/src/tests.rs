@@ -0,0 +1,1049 @@
+//! In-process tests of the server app, driven by calling `App::update` directly.
+
+mod harness;
+
+use super::*;
+use colors::SelectedColors;
+use config::{ConfigError, ConfigWatch, Overrides};
+use harness::{FRAME, Harness};
+use proptest::prelude::*;
+use protocol::directory::{DirectoryRequest, DirectoryResponse, read_message, write_message};
+use protocol::snapshot::dequantize;
+use protocol::{MAX_NAME_CHARS, ProfileRejection, ProtocolVersion};
+use std::env;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use store::{Batch, FileStore, RedisStore, Reply, SessionOwner, StateStore};
+
+/// Settings on free ports, so tests can run side by side
+fn test_settings() -> ServerSettings {
+    ServerSettings {
+        port: 0,
+        metrics_port: 0,
+        private_key: None,
+        network_conditions: None,
+        ..ServerSettings::default()
+    }
+}
+
+/// Run frames for a while, long enough for a few fixed ticks
+fn run_frames(app: &mut App) {
+    for _ in 0..10 {
+        app.update();
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Status code and body of a GET request to the app's HTTP endpoints
+fn get(app: &App, path: &str) -> (u16, String) {
+    let port = app.world().resource::<ServerSettings>().metrics_port;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string();
+    (status, body)
+}
+
+/// Wait for the store thread to write everything queued so far
+fn flush_store(app: &App) {
+    assert!(app.world().resource::<PlayerStore>().flush(Duration::from_secs(5)));
+}
+
+/// Empty directory unique to one test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("server-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Stand-in for a Redis server that only knows GET, SET and DEL, returns its address
+fn fake_redis() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data = Arc::new(Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let data = data.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.unwrap());
+                while let Ok(Reply::Array(args)) = store::read_reply(&mut reader) {
+                    let args: Vec<Vec<u8>> = args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            Reply::Bulk(Some(arg)) => arg,
+                            other => panic!("unexpected argument {:?}", other),
+                        })
+                        .collect();
+                    let reply = match (args[0].as_slice(), &args[1..]) {
+                        (b"GET", [key]) => match data.lock().unwrap().get(key) {
+                            Some(value) => [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat(),
+                            None => b"$-1\r\n".to_vec(),
+                        },
+                        (b"SET", [key, value]) => {
+                            data.lock().unwrap().insert(key.clone(), value.clone());
+                            b"+OK\r\n".to_vec()
+                        }
+                        (b"DEL", [key]) => format!(":{}\r\n", data.lock().unwrap().remove(key).iter().count()).into_bytes(),
+                        _ => b"-ERR unknown command\r\n".to_vec(),
+                    };
+                    reader.get_mut().write_all(&reply).unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Saving then loading gives back the same state, and unknown players have none
+fn assert_round_trip(store: &dyn StateStore) {
+    let state = PlayerState {
+        translation: Vec3::new(12.5, 0.5, -7.25),
+        color: [0.1, 0.2, 0.3, 1.0],
+    };
+    assert_eq!(store.load(ClientId::from(7u64)).unwrap(), None);
+    store.save(ClientId::from(7u64), &state).unwrap();
+    assert_eq!(store.load(ClientId::from(7u64)).unwrap(), Some(state));
+    assert_eq!(store.load(ClientId::from(8u64)).unwrap(), None);
+
+    let token = SessionToken([3; 16]);
+    let owner = SessionOwner {
+        replica: "game-0".to_string(),
+        address: "10.0.0.2:5000".parse().unwrap(),
+        client_id: ClientId::from(7u64),
+        expires_at: 1234,
+    };
+    assert_eq!(store.owner(&token).unwrap(), None);
+    store.set_owner(&token, &owner).unwrap();
+    assert_eq!(store.owner(&token).unwrap(), Some(owner.clone()));
+    store.remove_owner(&token).unwrap();
+    assert_eq!(store.owner(&token).unwrap(), None);
+    store.remove_owner(&token).unwrap();
+}
+
+#[test]
+fn file_store_round_trip() {
+    let dir = temp_dir("file-store");
+    assert_round_trip(&FileStore::new(&dir).unwrap());
+    // A new store on the same directory, as after a restart
+    assert!(FileStore::new(&dir).unwrap().load(ClientId::from(7u64)).unwrap().is_some());
+}
+
+#[test]
+fn redis_store_round_trip() {
+    let store = RedisStore::new(&fake_redis().to_string());
+    assert_round_trip(&store);
+    assert!(store.command(&[b"PING"]).is_err());
+    // The connection is reopened after an error
+    assert!(store.load(ClientId::from(7u64)).unwrap().is_some());
+
+    let state = PlayerState {
+        translation: Vec3::ONE,
+        color: [1.0; 4],
+    };
+    let owner = SessionOwner {
+        replica: "game-1".to_string(),
+        address: "10.0.0.3:5000".parse().unwrap(),
+        client_id: ClientId::from(9u64),
+        expires_at: 99,
+    };
+    let token = SessionToken([4; 16]);
+    store
+        .write(&Batch {
+            states: vec![(ClientId::from(8u64), state), (ClientId::from(9u64), state)],
+            owners: vec![(token, owner.clone())],
+        })
+        .unwrap();
+    assert_eq!(store.load(ClientId::from(8u64)).unwrap(), Some(state));
+    assert_eq!(store.load(ClientId::from(9u64)).unwrap(), Some(state));
+    assert_eq!(store.owner(&token).unwrap(), Some(owner));
+}
+
+#[test]
+fn redis_store_reports_unreachable_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    assert!(RedisStore::new(&addr.to_string()).load(ClientId::from(7u64)).is_err());
+}
+
+#[test]
+fn connected_players_are_saved_periodically() {
+    let dir = temp_dir("periodic-save");
+    let mut app = build_app(ServerSettings {
+        state_store: Some(format!("file:{}", dir.display())),
+        state_save_interval: 0.01,
+        ..test_settings()
+    });
+    app.world_mut().spawn((
+        Transform::from_xyz(3.0, 0.5, 4.0),
+        Player { id: ClientId::from(42u64) },
+        PlayerColor([1.0, 0.0, 0.0, 1.0]),
+    ));
+    run_frames(&mut app);
+    flush_store(&app);
+    let saved = FileStore::new(&dir).unwrap().load(ClientId::from(42u64)).unwrap().unwrap();
+    assert_eq!(saved.translation, Vec3::new(3.0, 0.5, 4.0));
+    assert_eq!(saved.color, [1.0, 0.0, 0.0, 1.0]);
+}
+
+#[test]
+fn unresponsive_store_does_not_hold_up_ticks() {
+    // Accepts connections but never answers, every command waits out the Redis timeout
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut app = build_app(ServerSettings {
+        state_store: Some(format!("redis://{}", listener.local_addr().unwrap())),
+        state_save_interval: 0.01,
+        ..test_settings()
+    });
+    app.world_mut().spawn((
+        Transform::from_xyz(3.0, 0.5, 4.0),
+        Player { id: ClientId::from(42u64) },
+        PlayerColor([1.0, 0.0, 0.0, 1.0]),
+    ));
+    let started = std::time::Instant::now();
+    run_frames(&mut app);
+    assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+}
+
+#[test]
+fn saved_players_are_restored_when_they_join() {
+    let dir = temp_dir("restore-on-join");
+    let saved = PlayerState {
+        translation: Vec3::new(6.0, 0.5, -2.0),
+        color: [0.0, 0.0, 1.0, 1.0],
+    };
+    FileStore::new(&dir).unwrap().save(ClientId::from(5u64), &saved).unwrap();
+    let mut harness = Harness::new(ServerSettings {
+        state_store: Some(format!("file:{}", dir.display())),
+        ..test_settings()
+    });
+    let client = harness.connect(5);
+    harness.run_until("the restored player to join", |h| {
+        h.inbox(client)
+            .messages
+            .iter()
+            .any(|message| matches!(message, ServerMessages::PlayerConnected { .. }))
+    });
+    assert_eq!(harness.player_translation(5), Some(saved.translation));
+    assert!(harness.inbox(client).messages.iter().any(|message| matches!(
+        message,
+        ServerMessages::PlayerConnected { color, .. } if *color == saved.color
+    )));
+
+    // The restored player never moves, still a client joining later shows it where it was saved
+    let other = harness.connect(6);
+    harness.run_until("the other client to show the restored player where it was saved", |h| {
+        h.client_player_position(other, 5)
+            .is_some_and(|position| position.distance(saved.translation) < 0.01)
+    });
+}
+
+#[test]
+fn sessions_held_by_another_replica_are_redirected() {
+    let dir = temp_dir("redirect");
+    let mut harness = Harness::new(ServerSettings {
+        state_store: Some(format!("file:{}", dir.display())),
+        ..test_settings()
+    });
+    // Another replica behind the same address, as when replicas share a load balancer address
+    let address = harness.server.world().resource::<ServerSettings>().public_address();
+    let token = SessionToken([5; 16]);
+    let owner = SessionOwner {
+        replica: "another-replica".to_string(),
+        address,
+        client_id: ClientId::from(3u64),
+        expires_at: unix_time() + 60,
+    };
+    FileStore::new(&dir).unwrap().set_owner(&token, &owner).unwrap();
+    let client = harness.connect_with(
+        4,
+        Handshake {
+            session: Some(token),
+            ..Handshake::current()
+        },
+    );
+    harness.run_until("the redirect", |h| {
+        h.inbox(client)
+            .messages
+            .iter()
+            .any(|message| matches!(message, ServerMessages::Redirect { address: to } if *to == address))
+    });
+    assert_eq!(harness.player_translation(4), None);
+}
+
+#[test]
+fn session_leases_are_renewed_then_handed_over_on_shutdown() {
+    let dir = temp_dir("session-lease");
+    let mut app = build_app(ServerSettings {
+        state_store: Some(format!("file:{}", dir.display())),
+        state_save_interval: 0.01,
+        session_lease: 60.0,
+        drain_period: 0.0,
+        ..test_settings()
+    });
+    let token = SessionToken([9; 16]);
+    app.world_mut().spawn((
+        Transform::from_xyz(1.0, 0.5, 2.0),
+        Player { id: ClientId::from(42u64) },
+        PlayerColor([0.0, 1.0, 0.0, 1.0]),
+        Session(token),
+    ));
+    run_frames(&mut app);
+    flush_store(&app);
+    let store = FileStore::new(&dir).unwrap();
+    let owner = store.owner(&token).unwrap().unwrap();
+    assert_eq!(owner.replica, app.world().resource::<ServerSettings>().replica_id);
+    assert_eq!(owner.client_id, ClientId::from(42u64));
+    assert!(!owner.is_expired(unix_time()));
+
+    app.world().resource::<ShutdownSignal>().0.store(true, Ordering::SeqCst);
+    // The process ends with the frame that exits, nothing runs after it
+    for _ in 0..10 {
+        app.update();
+        if app.should_exit().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(app.should_exit().is_some());
+    assert!(store.owner(&token).unwrap().unwrap().is_expired(unix_time()));
+}
+
+#[test]
+fn announces_itself_to_the_directory() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut app = build_app(ServerSettings {
+        directory_addr: Some(listener.local_addr().unwrap().to_string()),
+        ..test_settings()
+    });
+    // Nothing to announce before the first frame published the player counts and readiness
+    listener.set_nonblocking(true).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(listener.accept().is_err());
+    listener.set_nonblocking(false).unwrap();
+
+    run_frames(&mut app);
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.set_nonblocking(false).unwrap();
+    let request: DirectoryRequest = read_message(&mut stream).unwrap();
+    write_message(&mut stream, &DirectoryResponse::Registered).unwrap();
+    let DirectoryRequest::Heartbeat(status) = request else {
+        panic!("expected a heartbeat, got {:?}", request);
+    };
+    assert_eq!(status.address, app.world().resource::<ServerSettings>().public_address());
+    assert_eq!(status.version, ProtocolVersion::current());
+    assert_eq!(status.capacity, app.world().resource::<ServerSettings>().max_clients);
+    assert!(status.accepting);
+}
+
+#[test]
+fn healthy_server_is_live_and_ready() {
+    let mut app = build_app(test_settings());
+    run_frames(&mut app);
+    assert_eq!(get(&app, "/healthz").0, 200);
+    assert_eq!(get(&app, "/readyz").0, 200);
+    assert_eq!(get(&app, "/nope").0, 404);
+}
+
+#[test]
+fn idle_connections_do_not_hold_up_probes() {
+    let mut app = build_app(test_settings());
+    run_frames(&mut app);
+    let port = app.world().resource::<ServerSettings>().metrics_port;
+    // Connected but never sending a request, like a stuck scraper
+    let _idle: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap()).collect();
+    let started = std::time::Instant::now();
+    assert_eq!(get(&app, "/healthz").0, 200);
+    assert!(started.elapsed() < Duration::from_millis(200), "took {:?}", started.elapsed());
+}
+
+#[test]
+fn not_ready_at_max_clients() {
+    let mut app = build_app(ServerSettings {
+        max_clients: 0,
+        ..test_settings()
+    });
+    run_frames(&mut app);
+    assert_eq!(get(&app, "/healthz").0, 200);
+    let (status, body) = get(&app, "/readyz");
+    assert_eq!(status, 503);
+    assert!(body.contains("max clients"), "{}", body);
+}
+
+#[test]
+fn not_ready_while_draining() {
+    let mut app = build_app(test_settings());
+    run_frames(&mut app);
+    app.insert_resource(ShuttingDown {
+        started: f64::MAX,
+        delay: 0.0,
+        exit: AppExit::Success,
+    });
+    run_frames(&mut app);
+    assert_eq!(get(&app, "/healthz").0, 200);
+    let (status, body) = get(&app, "/readyz");
+    assert_eq!(status, 503);
+    assert!(body.contains("draining"), "{}", body);
+}
+
+#[test]
+fn signal_drains_then_exits() {
+    let mut app = build_app(ServerSettings {
+        drain_period: 0.3,
+        ..test_settings()
+    });
+    run_frames(&mut app);
+    app.world().resource::<ShutdownSignal>().0.store(true, Ordering::SeqCst);
+    run_frames(&mut app);
+    let (status, body) = get(&app, "/readyz");
+    assert_eq!(status, 503);
+    assert!(body.contains("draining"), "{}", body);
+    assert_eq!(app.should_exit(), None);
+
+    thread::sleep(Duration::from_millis(300));
+    run_frames(&mut app);
+    assert_eq!(app.should_exit(), Some(AppExit::Success));
+}
+
+#[test]
+fn watchdog_fires_when_schedule_stalls() {
+    let mut app = build_app(ServerSettings {
+        tick_watchdog_timeout: 0.2,
+        ..test_settings()
+    });
+    run_frames(&mut app);
+    assert_eq!(get(&app, "/readyz").0, 200);
+
+    // Stop calling update, as if a system had hung
+    thread::sleep(Duration::from_millis(400));
+    let (status, body) = get(&app, "/healthz");
+    assert_eq!(status, 503);
+    assert!(body.contains("no tick"), "{}", body);
+    assert_eq!(get(&app, "/readyz").0, 503);
+
+    run_frames(&mut app);
+    assert_eq!(get(&app, "/healthz").0, 200);
+}
+
+#[test]
+fn metrics_are_served() {
+    let mut app = build_app(test_settings());
+    run_frames(&mut app);
+    let (status, body) = get(&app, "/metrics");
+    assert_eq!(status, 200);
+    assert!(body.contains("game_connected_clients 0"), "{}", body);
+}
+
+#[test]
+fn transport_errors_only_exit_when_the_socket_fails() {
+    let mut harness = Harness::new(test_settings());
+    harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+
+    // Some peer went away, nobody else should be disconnected for it
+    let refused = NetcodeTransportError::IO(std::io::ErrorKind::ConnectionRefused.into());
+    assert_eq!(classify_transport_error(&refused), TransportErrorAction::Count);
+    harness.server.world_mut().send_event(refused);
+    harness.step_frames(60);
+    assert!(harness.server.world().resource::<RenetServer>().is_connected(ClientId::from(1u64)));
+    assert_eq!(harness.server.world().resource::<TransportErrors>().0, 1);
+    assert_eq!(harness.server.should_exit(), None);
+
+    let broken = NetcodeTransportError::IO(std::io::ErrorKind::InvalidInput.into());
+    assert_eq!(classify_transport_error(&broken), TransportErrorAction::Exit);
+    harness.server.world_mut().send_event(broken);
+    harness.run_until("the server to exit", |h| h.server.should_exit().is_some());
+    assert_eq!(harness.server.should_exit(), Some(AppExit::from_code(1)));
+}
+
+#[test]
+fn fatal_transport_errors_warn_clients_and_save_players_before_exiting() {
+    let dir = temp_dir("fatal-transport-error");
+    let mut harness = Harness::new(ServerSettings {
+        state_store: Some(format!("file:{}", dir.display())),
+        // Only the shutdown saves the player
+        state_save_interval: 3600.0,
+        ..test_settings()
+    });
+    let a = harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+    harness.hold(
+        a,
+        PlayerInput {
+            right: true,
+            ..Default::default()
+        },
+    );
+    harness.step_frames(10);
+    harness.hold(a, PlayerInput::default());
+    harness.step_frames(10);
+    let translation = harness.player_translation(1).unwrap();
+    assert_eq!(FileStore::new(&dir).unwrap().load(ClientId::from(1u64)).unwrap(), None);
+
+    harness
+        .server
+        .world_mut()
+        .send_event(NetcodeTransportError::IO(std::io::ErrorKind::InvalidInput.into()));
+    harness.run_until("the server to exit", |h| h.server.should_exit().is_some());
+    assert_eq!(harness.server.should_exit(), Some(AppExit::from_code(1)));
+    assert!(
+        harness
+            .inbox(a)
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessages::ServerShuttingDown { reconnect_hint: Some(_) }))
+    );
+    // The shutdown flushed the store before exiting
+    let saved = FileStore::new(&dir).unwrap().load(ClientId::from(1u64)).unwrap().unwrap();
+    assert!(
+        saved.translation.distance(translation) < 1e-3,
+        "saved {}, was at {}",
+        saved.translation,
+        translation
+    );
+}
+
+#[test]
+fn players_are_told_about_each_other() {
+    let mut harness = Harness::new(test_settings());
+    let a = harness.connect(1);
+    harness.run_until("the first player to join", |h| {
+        h.inbox(a)
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessages::PlayerConnected { id, .. } if *id == ClientId::from(1u64)))
+    });
+    let messages = &harness.inbox(a).messages;
+    assert!(matches!(messages[0], ServerMessages::SessionAssigned { .. }), "{:?}", messages);
+    assert!(matches!(messages[1], ServerMessages::Simulation { .. }), "{:?}", messages);
+
+    let b = harness.connect(2);
+    harness.run_until("the first player to see the second join", |h| {
+        h.inbox(a)
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessages::PlayerConnected { id, .. } if *id == ClientId::from(2u64)))
+    });
+    harness.disconnect(b);
+    harness.run_until("the first player to see the second leave", |h| {
+        h.inbox(a)
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessages::PlayerDisconnected { id } if *id == ClientId::from(2u64)))
+    });
+}
+
+#[test]
+fn disconnected_players_are_cleaned_up_after_grace_period() {
+    let mut harness = Harness::new(ServerSettings {
+        client_disconnect_grace_period: 0.5,
+        ..test_settings()
+    });
+    let a = harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+    harness.disconnect(a);
+    harness.run_until("the server to notice the disconnect", |h| {
+        !h.server.world().resource::<RenetServer>().is_connected(ClientId::from(1u64))
+    });
+
+    // Kept for the whole grace period, then despawned
+    harness.step_frames((0.4 / FRAME.as_secs_f64()) as usize);
+    assert!(harness.player_translation(1).is_some());
+    harness.step_frames((0.2 / FRAME.as_secs_f64()) as usize);
+    assert_eq!(harness.player_translation(1), None);
+    assert!(harness.server.world().resource::<Lobby>().players.is_empty());
+}
+
+#[test]
+fn reconnecting_with_a_live_session_takes_over_the_player() {
+    let mut harness = Harness::new(test_settings());
+    let old = harness.connect(1);
+    harness.run_until("a session to be assigned", |h| h.session(old).is_some());
+    let entity = harness.server.world().resource::<Lobby>().players[&ClientId::from(1u64)];
+
+    // The old connection has not timed out yet when the client comes back with a new id
+    let new = harness.connect_with(
+        2,
+        Handshake {
+            session: harness.session(old),
+            take_over: true,
+            ..Handshake::current()
+        },
+    );
+    harness.run_until("the new connection to get its own session", |h| h.session(new).is_some());
+    harness.run_until("the old connection to be dropped", |h| !h.is_connected(old));
+    assert_eq!(harness.player_translation(1), None);
+    assert!(harness.player_translation(2).is_some());
+    let lobby = &harness.server.world().resource::<Lobby>().players;
+    assert_eq!(lobby.len(), 1);
+    assert_eq!(lobby[&ClientId::from(2u64)], entity);
+    assert_ne!(harness.session(new), harness.session(old));
+}
+
+#[test]
+fn live_sessions_are_only_taken_over_when_asked() {
+    let mut harness = Harness::new(test_settings());
+    let first = harness.connect(1);
+    harness.run_until("a session to be assigned", |h| h.session(first).is_some());
+
+    // Another client that found the same session, e.g. started from the same directory
+    let second = harness.connect_with(
+        2,
+        Handshake {
+            session: harness.session(first),
+            ..Handshake::current()
+        },
+    );
+    harness.run_until("the second client to be refused", |h| {
+        h.inbox(second).messages.iter().any(|m| {
+            matches!(
+                m,
+                ServerMessages::ConnectionRejected {
+                    reason: RejectReason::ClientIdInUse
+                }
+            )
+        })
+    });
+    harness.step_frames(10);
+    assert!(harness.is_connected(first));
+    assert!(harness.player_translation(1).is_some());
+    assert_eq!(harness.player_translation(2), None);
+}
+
+#[test]
+fn garbage_from_a_client_is_dropped_until_it_sends_too_much() {
+    let mut harness = Harness::new(ServerSettings {
+        max_decode_errors: 4,
+        ..test_settings()
+    });
+    let a = harness.connect(1);
+    harness.connect(2);
+    harness.run_until("both players to spawn", |h| {
+        h.player_translation(1).is_some() && h.player_translation(2).is_some()
+    });
+    let connected = |h: &Harness, id: u64| h.server.world().resource::<RenetServer>().is_connected(ClientId::from(id));
+
+    // An unknown message, a truncated one and a bad snapshot ack are dropped, and the game goes on
+    harness.send_raw(a, DefaultChannel::ReliableOrdered, vec![0xff; 8]);
+    harness.send_raw(a, DefaultChannel::ReliableOrdered, vec![0, 0]);
+    harness.send_raw(a, DefaultChannel::Unreliable, vec![1, 2, 3]);
+    harness.step_frames(10);
+    let tick = harness.server.world().resource::<ServerTick>().0;
+    harness.step_frames(10);
+    assert!(harness.server.world().resource::<ServerTick>().0 > tick);
+    assert_eq!(harness.server.world().resource::<DecodeErrors>().counts[&ClientId::from(1u64)].0, 3);
+    assert!(connected(&harness, 1));
+
+    harness.send_raw(a, DefaultChannel::Unreliable, vec![0xff; 16]);
+    harness.run_until("the server to disconnect the client", |h| !connected(h, 1));
+    assert!(connected(&harness, 2));
+    assert!(harness.player_translation(2).is_some());
+}
+
+#[test]
+fn decode_errors_are_counted_per_window() {
+    let mut server = RenetServer::new(ConnectionConfig::default());
+    let settings = ServerSettings {
+        max_decode_errors: 2,
+        ..test_settings()
+    };
+    let mut decode_errors = DecodeErrors::default();
+    let id = ClientId::from(1u64);
+    let error = protocol::decode::<ClientMessages>(&[0xff]).unwrap_err();
+    let mut record = |now| record_decode_error(&mut server, &mut decode_errors, &settings, id, 1, &error, now);
+
+    assert!(!record(0.0));
+    // The first error is long forgotten by the next one
+    assert!(!record(DECODE_ERROR_WINDOW + 1.0));
+    assert!(record(DECODE_ERROR_WINDOW + 2.0));
+}
+
+#[test]
+fn held_input_moves_the_player() {
+    let mut harness = Harness::new(ServerSettings {
+        player_move_speed: 30.0,
+        ..test_settings()
+    });
+    let a = harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+    let start = harness.player_translation(1).unwrap();
+
+    // Every input is applied on exactly one tick, so a second of right moves the player by its speed
+    harness.hold(
+        a,
+        PlayerInput {
+            right: true,
+            ..Default::default()
+        },
+    );
+    harness.step_frames(60);
+    harness.hold(a, PlayerInput::default());
+    harness.step_frames(10);
+    let end = harness.player_translation(1).unwrap();
+    assert!((end.x - start.x - 30.0).abs() < 1e-3, "moved from {} to {}", start, end);
+    assert_eq!(end.z, start.z);
+
+    // The client ends up with the same position from its snapshots, and its prediction agrees
+    let seen = dequantize(harness.client_state(a)[&ClientId::from(1u64)]);
+    assert!(seen.distance(end) < 0.01, "client saw {}, server has {}", seen, end);
+    let predicted = harness.client_position(a).unwrap();
+    assert!(predicted.distance(end) < 0.01, "client predicted {}, server has {}", predicted, end);
+}
+
+#[test]
+fn config_layers_take_precedence() {
+    let dir = temp_dir("config-layers");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.toml");
+    std::fs::write(&path, "port = 6000\nmax_clients = 3\nplayer_move_speed = 10.0\n").unwrap();
+
+    let cli = Overrides::try_parse_from(["server", "--config", path.to_str().unwrap(), "--player-move-speed", "20"]).unwrap();
+    let settings = config::load(&cli).unwrap();
+    assert_eq!(settings.player_move_speed, 20.0);
+    assert_eq!(settings.max_clients, 3);
+    assert_eq!(
+        settings.client_disconnect_grace_period,
+        ServerSettings::default().client_disconnect_grace_period
+    );
+    // Clients are told to use the port from the file
+    assert_eq!(settings.public_addresses, vec![SocketAddr::from(([127, 0, 0, 1], 6000))]);
+}
+
+#[test]
+fn invalid_config_refuses_to_start() {
+    assert!(Overrides::try_parse_from(["server", "--player-move-speed", "fast"]).is_err());
+    let cli = Overrides::try_parse_from(["server", "--max-clients", "0"]).unwrap();
+    assert!(matches!(
+        config::load(&cli),
+        Err(ConfigError::Invalid {
+            setting: "max_clients",
+            ..
+        })
+    ));
+
+    let dir = temp_dir("config-invalid");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.toml");
+    std::fs::write(&path, "player_move_sped = 10.0\n").unwrap();
+    let cli = Overrides {
+        config: Some(path),
+        ..Default::default()
+    };
+    assert!(matches!(config::load(&cli), Err(ConfigError::Parse { .. })));
+
+    // NaN and infinity are not mistaken for negative numbers, and never pass as a lease
+    let reason = |settings: ServerSettings| match settings.validate() {
+        Err(ConfigError::Invalid { setting, reason }) => (setting, reason),
+        other => panic!("expected an invalid setting, got {:?}", other),
+    };
+    let (setting, why) = reason(ServerSettings {
+        max_clients: config::NETCODE_MAX_CLIENTS + 1,
+        ..test_settings()
+    });
+    assert_eq!(
+        (setting, why.as_str()),
+        ("max_clients", "1025 is more than the 1024 netcode supports")
+    );
+    assert!(
+        ServerSettings {
+            max_clients: config::NETCODE_MAX_CLIENTS,
+            ..test_settings()
+        }
+        .validate()
+        .is_ok()
+    );
+    let (setting, why) = reason(ServerSettings {
+        player_move_speed: f32::INFINITY,
+        ..test_settings()
+    });
+    assert_eq!((setting, why.as_str()), ("player_move_speed", "inf is not a finite number"));
+    let (setting, why) = reason(ServerSettings {
+        drain_period: f64::NAN,
+        ..test_settings()
+    });
+    assert_eq!((setting, why.as_str()), ("drain_period", "NaN is not a finite number"));
+    let (setting, why) = reason(ServerSettings {
+        client_disconnect_grace_period: -1.0,
+        ..test_settings()
+    });
+    assert_eq!((setting, why.as_str()), ("client_disconnect_grace_period", "-1 is negative"));
+    for session_lease in [f64::NAN, f64::INFINITY] {
+        assert_eq!(
+            reason(ServerSettings {
+                session_lease,
+                ..test_settings()
+            })
+            .0,
+            "session_lease"
+        );
+    }
+
+    // Other replicas could not redirect clients to a loopback address
+    let cli = Overrides::try_parse_from(["server", "--state-store", "redis://redis:6379"]).unwrap();
+    assert!(matches!(
+        config::load(&cli),
+        Err(ConfigError::Invalid {
+            setting: "public_addresses",
+            ..
+        })
+    ));
+    let cli = Overrides::try_parse_from([
+        "server",
+        "--state-store",
+        "redis://redis:6379",
+        "--public-addresses",
+        "10.0.0.2:5000",
+    ])
+    .unwrap();
+    assert!(config::load(&cli).is_ok());
+}
+
+#[test]
+fn config_file_changes_are_reloaded_and_broadcast() {
+    let dir = temp_dir("config-reload");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.toml");
+    std::fs::write(&path, "player_move_speed = 10.0\n").unwrap();
+    let cli = Overrides {
+        config: Some(path.clone()),
+        ..Default::default()
+    };
+    let mut harness = Harness::new(ServerSettings {
+        player_move_speed: 10.0,
+        ..test_settings()
+    });
+    harness.server.insert_resource(ConfigWatch::new(cli).unwrap());
+    let a = harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+
+    // Settings that need a restart are left alone
+    std::fs::write(&path, "player_move_speed = 25.0\nmax_clients = 1\n").unwrap();
+    harness.run_until("the client to hear about the new speed", |h| {
+        h.inbox(a)
+            .messages
+            .iter()
+            .any(|m| matches!(m, ServerMessages::SimulationChanged { settings } if settings.player_move_speed == 25.0))
+    });
+    let settings = harness.server.world().resource::<ServerSettings>();
+    assert_eq!(settings.player_move_speed, 25.0);
+    assert_eq!(settings.max_clients, ServerSettings::default().max_clients);
+
+    // An invalid file keeps the running settings
+    std::fs::write(&path, "player_move_speed = -1.0\n").unwrap();
+    harness.step_frames(120);
+    assert_eq!(harness.server.world().resource::<ServerSettings>().player_move_speed, 25.0);
+}
+
+/// The first candidate every player can tell apart from the others, the one a joining player should get
+fn first_free_color(selected: &SelectedColors, id: ClientId, mode: PaletteMode) -> Option<[f32; 4]> {
+    selected
+        .modes(mode)
+        .into_iter()
+        .flat_map(colors::candidates)
+        .find(|candidate| colors::is_distinct(selected, id, candidate, mode))
+}
+
+/// The same players and preferences in fresh maps, which iterate in a different order
+fn reshuffled(selected: &SelectedColors) -> SelectedColors {
+    SelectedColors {
+        colors: selected.colors.iter().map(|(&id, &color)| (id, color)).collect(),
+        preferences: selected.preferences.iter().map(|(&id, &mode)| (id, mode)).collect(),
+    }
+}
+
+/// Every two colors are at least the threshold apart as seen with each of `modes`
+fn assert_colors_apart(selected: &SelectedColors, modes: &[PaletteMode]) -> Result<(), TestCaseError> {
+    let colors: Vec<_> = selected.colors.values().collect();
+    for (i, a) in colors.iter().enumerate() {
+        for b in &colors[i + 1..] {
+            for &mode in modes {
+                let distance = colors::color_distance(mode, a, b);
+                prop_assert!(
+                    distance >= colors::threshold(mode),
+                    "{:?} and {:?} are {} apart in {:?}",
+                    a,
+                    b,
+                    distance,
+                    mode
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn palette_mode() -> impl Strategy<Value = PaletteMode> {
+    prop_oneof![
+        Just(PaletteMode::Default),
+        Just(PaletteMode::Deuteranopia),
+        Just(PaletteMode::Protanopia),
+        Just(PaletteMode::HighContrast),
+    ]
+}
+
+proptest! {
+    // Every pick scores each candidate against every live player, with up to 40 of them fewer cases keep it quick
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn live_player_colors_stay_apart(
+        mode in palette_mode(),
+        events in proptest::collection::vec((0u64..40, any::<bool>(), proptest::option::of(palette_mode())), 1..200),
+    ) {
+        let mut selected = SelectedColors::default();
+        for (id, join, preference) in events {
+            let id = ClientId::from(id);
+            if join && !selected.colors.contains_key(&id) {
+                if let Some(preference) = preference {
+                    selected.preferences.insert(id, preference);
+                }
+                // More players than the palettes have distinct colors for fall back to the closest one
+                let free = first_free_color(&selected, id, mode);
+                let fallback = pick_new_player_color(&mut reshuffled(&selected), id, mode);
+                let color = pick_new_player_color(&mut selected, id, mode);
+                prop_assert_eq!(color, fallback, "the pick depends on the order players are stored in");
+                if let Some(free) = free {
+                    // Released colors are candidates again, so the first free one may be a reused color
+                    prop_assert_eq!(color, free);
+                    prop_assert!(colors::is_distinct(&selected, id, &color, mode));
+                }
+            } else if !join {
+                selected.release(id);
+            }
+        }
+    }
+}
+
+#[test]
+fn released_colors_are_reused() {
+    let mode = PaletteMode::HighContrast;
+    let mut selected = SelectedColors::default();
+    let mut id = 0u64;
+    while first_free_color(&selected, ClientId::from(id), mode).is_some() {
+        pick_new_player_color(&mut selected, ClientId::from(id), mode);
+        id += 1;
+    }
+    // Past the palette size, the newcomer shares a color
+    let crowded = pick_new_player_color(&mut selected, ClientId::from(id), mode);
+    assert!(!colors::is_distinct(&selected, ClientId::from(id), &crowded, mode));
+
+    let released = selected.release(ClientId::from(0u64)).unwrap();
+    selected.release(ClientId::from(id));
+    assert_eq!(pick_new_player_color(&mut selected, ClientId::from(id + 1), mode), released);
+}
+
+#[test]
+fn colors_are_told_apart_by_color_blind_players() {
+    let mut selected = SelectedColors::default();
+    selected.preferences.insert(ClientId::from(1u64), PaletteMode::Deuteranopia);
+    for id in 1..=5u64 {
+        pick_new_player_color(&mut selected, ClientId::from(id), PaletteMode::Default);
+    }
+    assert_colors_apart(&selected, &[PaletteMode::Default, PaletteMode::Deuteranopia]).unwrap();
+}
+
+#[test]
+fn colors_are_perceptually_distinct() {
+    let red = [1.0, 0.0, 0.0, 1.0];
+    let green = [0.0, 1.0, 0.0, 1.0];
+    let dark_red = [0.9, 0.0, 0.0, 1.0];
+    assert!(colors::color_distance(PaletteMode::Default, &red, &green) > colors::threshold(PaletteMode::Default));
+    assert!(colors::color_distance(PaletteMode::Default, &red, &dark_red) < colors::threshold(PaletteMode::Default));
+    // Hard to tell apart without green cones
+    let olive = [0.2, 0.2, 0.0, 1.0];
+    let brown = [0.35, 0.1, 0.0, 1.0];
+    assert!(
+        colors::color_distance(PaletteMode::Default, &olive, &brown) > colors::color_distance(PaletteMode::Deuteranopia, &olive, &brown)
+    );
+}
+
+#[test]
+fn colors_are_released_on_cleanup() {
+    let mut harness = Harness::new(ServerSettings {
+        client_disconnect_grace_period: 0.0,
+        ..test_settings()
+    });
+    let a = harness.connect(1);
+    harness.run_until("the player to spawn", |h| h.player_translation(1).is_some());
+    assert!(
+        harness
+            .server
+            .world()
+            .resource::<SelectedColors>()
+            .colors
+            .contains_key(&ClientId::from(1u64))
+    );
+    harness.disconnect(a);
+    harness.run_until("the player to be cleaned up", |h| h.player_translation(1).is_none());
+    assert!(harness.server.world().resource::<SelectedColors>().colors.is_empty());
+}
+
+#[test]
+fn names_are_validated() {
+    assert_eq!(profile::validate_name("  Ferris the crab ").unwrap(), "Ferris the crab");
+    assert_eq!(profile::validate_name(""), Err(ProfileRejection::NameLength));
+    assert_eq!(
+        profile::validate_name("a".repeat(MAX_NAME_CHARS + 1).as_str()),
+        Err(ProfileRejection::NameLength)
+    );
+    assert_eq!(profile::validate_name("<script>"), Err(ProfileRejection::NameCharacters));
+    assert_eq!(profile::validate_name("Crab\u{200B}"), Err(ProfileRejection::NameCharacters));
+    assert_eq!(profile::validate_name("sh1t_head"), Err(ProfileRejection::NameNotAllowed));
+    assert_eq!(profile::validate_name("B.I.T.C.H"), Err(ProfileRejection::NameNotAllowed));
+}
+
+#[test]
+fn profiles_are_broadcast_and_checked() {
+    let mut harness = Harness::new(test_settings());
+    let a = harness.connect(1);
+    let b = harness.connect(2);
+    harness.run_until("both players to spawn", |h| {
+        h.client_state(a).len() == 2 && h.client_state(b).len() == 2
+    });
+
+    let orange = [1.0, 0.2, 0.0, 1.0];
+    harness.send(
+        a,
+        &ClientMessages::SetProfile {
+            name: "Ferris".to_string(),
+            preferred_color: Some(orange),
+        },
+    );
+    harness.run_until("the other player to hear about the profile", |h| {
+        h.inbox(b).messages.iter().any(|m| {
+            matches!(m, ServerMessages::ProfileChanged { id, name, color } if *id == ClientId::from(1u64) && name == "Ferris" && *color == orange)
+        })
+    });
+
+    // Another player asking for nearly the same color is refused
+    harness.send(
+        b,
+        &ClientMessages::SetProfile {
+            name: "Corro".to_string(),
+            preferred_color: Some([0.98, 0.21, 0.0, 1.0]),
+        },
+    );
+    harness.run_until("the profile to be refused", |h| {
+        h.inbox(b).messages.iter().any(|m| {
+            matches!(
+                m,
+                ServerMessages::ProfileRejected {
+                    reason: ProfileRejection::ColorTaken
+                }
+            )
+        })
+    });
+
+    // Players that come into view later learn the name with the player
+    let c = harness.connect(3);
+    harness.run_until("the new player to see the named one", |h| {
+        h.inbox(c).messages.iter().any(
+            |m| matches!(m, ServerMessages::SpawnPlayer { id, name: Some(name), .. } if *id == ClientId::from(1u64) && name == "Ferris"),
+        )
+    });
+}
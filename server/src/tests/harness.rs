@@ -0,0 +1,198 @@
+//! The server app and headless client apps in one process, talking over loopback UDP.
+//!
+//! The clients run the same networking as the real ones, [`SyncPlugin`] from `client-common`, without drawing
+//! anything. Every app advances its clock by exactly [`FRAME`] per update, one fixed tick, so a test that steps
+//! the same number of frames always sees the same ticks, timeouts and movement.
+
+use super::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy_renet::RenetClientPlugin;
+use bevy_renet::netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport};
+use bevy_renet::renet::RenetClient;
+use client_common::{ClientSettings, LocalPlayer, NetworkSync, PlayerPosition, ServerMessageReceived, SyncPlugin};
+use protocol::snapshot::SnapshotReceiver;
+
+/// Simulated time per update of every app, one server tick
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Most frames [`Harness::run_until`] waits for a condition
+const MAX_FRAMES: usize = 600;
+
+/// Every message a headless client received, in order
+#[derive(Debug, Default, Resource)]
+pub struct Inbox {
+    pub messages: Vec<ServerMessages>,
+}
+
+pub struct Harness {
+    pub server: App,
+    pub clients: Vec<App>,
+}
+
+impl Harness {
+    /// Start a server with `settings` on a free port, unsecured so clients pick their own ids
+    pub fn new(settings: ServerSettings) -> Self {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut server = build_app(ServerSettings {
+            port,
+            public_addresses: vec![SocketAddr::from(([127, 0, 0, 1], port))],
+            private_key: None,
+            metrics_port: 0,
+            tick_rate: 60.0,
+            ..settings
+        });
+        server
+            .insert_resource(TimeUpdateStrategy::ManualDuration(FRAME))
+            .insert_resource(Time::<Fixed>::from_duration(FRAME));
+        Self {
+            server,
+            clients: Vec::new(),
+        }
+    }
+
+    /// Add a headless client that starts connecting right away, returns its index in `clients`
+    pub fn connect(&mut self, client_id: u64) -> usize {
+        self.connect_with(client_id, Handshake::current())
+    }
+
+    /// Add a headless client presenting `handshake`, e.g. to reclaim a session
+    pub fn connect_with(&mut self, client_id: u64, handshake: Handshake) -> usize {
+        let server_addr = self.server.world().resource::<ServerSettings>().public_address();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let authentication = ClientAuthentication::Unsecure {
+            client_id,
+            protocol_id: PROTOCOL_ID,
+            server_addr,
+            user_data: Some(handshake.to_user_data()),
+        };
+        let mut client = App::new();
+        client
+            .add_plugins((
+                TimePlugin,
+                TaskPoolPlugin {
+                    task_pool_options: Default::default(),
+                },
+                RenetClientPlugin,
+                NetcodeClientPlugin,
+            ))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(FRAME))
+            .insert_resource(ClientSettings {
+                session_file: env::temp_dir().join(format!("harness-{}-{}.session", server_addr.port(), client_id)),
+                ..ClientSettings::default()
+            })
+            .insert_resource(RenetClient::new(ConnectionConfig::default()))
+            .insert_resource(NetcodeClientTransport::new(current_time, authentication, socket).unwrap())
+            .add_plugins(SyncPlugin)
+            .init_resource::<Inbox>()
+            .add_systems(Update, inbox_system.after(NetworkSync));
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    /// Tell the server a client is leaving, the way a client that quits does
+    pub fn disconnect(&mut self, index: usize) {
+        let world = self.clients[index].world_mut();
+        world.resource_mut::<RenetClient>().disconnect();
+        world.resource_mut::<NetcodeClientTransport>().disconnect();
+    }
+
+    /// Update the server, then every client, so what the server sent this frame is read in the same frame
+    pub fn step(&mut self) {
+        self.server.update();
+        for client in &mut self.clients {
+            client.update();
+        }
+    }
+
+    pub fn step_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.step();
+        }
+    }
+
+    /// Step until `done` holds, panicking if it does not within a few simulated seconds
+    pub fn run_until(&mut self, what: &str, mut done: impl FnMut(&mut Self) -> bool) {
+        for _ in 0..MAX_FRAMES {
+            if done(self) {
+                return;
+            }
+            self.step();
+        }
+        panic!("gave up waiting for {}", what);
+    }
+
+    pub fn inbox(&self, index: usize) -> &Inbox {
+        self.clients[index].world().resource::<Inbox>()
+    }
+
+    /// Send a message from a client, it leaves with the client's next update
+    pub fn send(&mut self, index: usize, message: &ClientMessages) {
+        let mut client = self.clients[index].world_mut().resource_mut::<RenetClient>();
+        client.send_message(DefaultChannel::ReliableOrdered, bincode::serialize(message).unwrap());
+    }
+
+    /// Send bytes as they are, e.g. garbage the server has to survive
+    pub fn send_raw(&mut self, index: usize, channel: DefaultChannel, bytes: Vec<u8>) {
+        let mut client = self.clients[index].world_mut().resource_mut::<RenetClient>();
+        client.send_message(channel, bytes);
+    }
+
+    pub fn hold(&mut self, index: usize, input: PlayerInput) {
+        self.clients[index].world_mut().insert_resource(input);
+    }
+
+    /// World state from the newest snapshot a client decoded
+    pub fn client_state(&self, index: usize) -> WorldState {
+        let snapshots = self.clients[index].world().resource::<SnapshotReceiver>();
+        snapshots.newest().map(|(_, state)| state.clone()).unwrap_or_default()
+    }
+
+    /// Where a client shows player `id`, `None` while it has no entity for it
+    pub fn client_player_position(&self, index: usize, id: u64) -> Option<Vec3> {
+        let world = self.clients[index].world();
+        let &entity = world.resource::<Lobby>().players.get(&ClientId::from(id))?;
+        world.get::<PlayerPosition>(entity).map(|position| position.0)
+    }
+
+    /// Where a client shows its own player, predicted from its input
+    pub fn client_position(&mut self, index: usize) -> Option<Vec3> {
+        let world = self.clients[index].world_mut();
+        world
+            .query_filtered::<&PlayerPosition, With<LocalPlayer>>()
+            .iter(world)
+            .next()
+            .map(|position| position.0)
+    }
+
+    /// Session token the server last assigned a client
+    pub fn session(&self, index: usize) -> Option<SessionToken> {
+        self.inbox(index).messages.iter().rev().find_map(|message| match message {
+            ServerMessages::SessionAssigned { token } => Some(*token),
+            _ => None,
+        })
+    }
+
+    /// Whether a client is still connected, from its side
+    pub fn is_connected(&self, index: usize) -> bool {
+        self.clients[index].world().resource::<RenetClient>().is_connected()
+    }
+
+    /// Where the server has a player, `None` once its entity is gone
+    pub fn player_translation(&mut self, id: u64) -> Option<Vec3> {
+        let id = ClientId::from(id);
+        self.server
+            .world_mut()
+            .query::<(&Player, &Transform)>()
+            .iter(self.server.world())
+            .find(|(player, _)| player.id == id)
+            .map(|(_, transform)| transform.translation)
+    }
+}
+
+/// Keep every message the client received
+fn inbox_system(mut received: EventReader<ServerMessageReceived>, mut inbox: ResMut<Inbox>) {
+    inbox
+        .messages
+        .extend(received.read().map(|ServerMessageReceived(message)| message.clone()));
+}